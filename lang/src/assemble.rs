@@ -1,10 +1,10 @@
-use std::{fmt::Debug, ops::Range};
+use std::{collections::HashMap, fmt::Debug, ops::Range};
 
 use color_eyre::Section;
 use itertools::Itertools;
 use thiserror::Error;
 use tracing::instrument;
-use vm::op::Opcode;
+use vm::op::{Mix, Opcode};
 
 use crate::parse::{Atom, Gtch};
 
@@ -14,59 +14,53 @@ pub fn assemble<'a>(
     gtch: impl IntoIterator<Item = &'a Gtch>,
     bytecode_size: usize,
 ) -> Result<Vec<u8>, eyre::Report> {
-    let bytecode = gtch
+    let (subs, main): (Vec<&Gtch>, Vec<&Gtch>) = gtch.into_iter().partition(|gtch| gtch.is_sub());
+
+    // Subroutines go after the main program, which ends in a Return so it can't fall through into them.
+    let mut errs = vec![];
+    let mut subroutines = HashMap::new();
+    let mut labels = HashMap::new();
+    let mut addr = lay_out(main.iter().copied(), 0, &mut labels, &mut errs) + 1;
+    for sub in &subs {
+        let Gtch::Sub { name, children } = sub else {
+            unreachable!()
+        };
+        if addr > 255 {
+            errs.push(AssembleError::Invalid(
+                "Subroutine starts beyond the max address (255)",
+            ));
+        }
+        if subroutines.insert(name.as_str(), addr).is_some() {
+            errs.push(AssembleError::DuplicateSubroutine(name.clone()));
+        }
+        addr = lay_out(children, addr, &mut labels, &mut errs) + 1;
+    }
+
+    let resolve = |name: &str| subroutines.get(name).copied();
+    let resolve_label = |name: &str| labels.get(name).copied();
+    let ret = || Ok(vec![Opcode::Return as u8]);
+    let mut bytecode = main
         .into_iter()
-        .map::<std::result::Result<Vec<u8>, AssembleError>, _>(|gtch| match gtch {
-            Gtch::Copy(i, j) => {
-                let j = j.clone().idx().ok_or(AssembleError(
-                    "Range cannot be used as second argument to Copy",
-                ))?;
-                Ok(match i {
-                    Atom::Idx(i) => vec![Opcode::Copy as u8, *i as u8, j as u8],
-                    Atom::Range(r) => {
-                        if r.is_empty() {
-                            return Err(AssembleError("Range must be nonempty"));
-                        }
-                        if r.len() + j > 255 {
-                            return Err(AssembleError("Range ends beyond the max index (255)"));
-                        }
-
-                        r.clone()
-                            .enumerate()
-                            .flat_map(|(i, k)| vec![Opcode::Copy as u8, k as u8, j as u8 + i as u8])
-                            .collect_vec()
-                    }
-                    Atom::PC => vec![Opcode::CopyFromSelf as u8, j as u8],
-                })
-            }
-            Gtch::Jump(i) => {
-                let i = i
-                    .clone()
-                    .idx()
-                    .ok_or(AssembleError("Range cannot be used as argument to Jump"))?;
-                Ok(vec![Opcode::Jump as u8, i as u8])
-            }
-            Gtch::Sample(i) => {
-                let i = i
-                    .clone()
-                    .idx()
-                    .ok_or(AssembleError("Cannot sample a range"))?;
-                Ok(vec![Opcode::Sample as u8, i as u8])
-            }
-            Gtch::Swap(i, j) => {
-                let i = i
-                    .clone()
-                    .idx()
-                    .ok_or(AssembleError("Range cannot be used as argument to Swap"))?;
-                let j = j
-                    .clone()
-                    .idx()
-                    .ok_or(AssembleError("Range cannot be used as argument to Swap"))?;
-                Ok(vec![Opcode::Swap as u8, i as u8, j as u8])
-            }
-            _ => unreachable!(),
-        });
-    let (bytecode, errs): (Vec<Vec<u8>>, Vec<AssembleError>) = bytecode.partition_result();
+        .map(|gtch| assemble_op(gtch, &resolve, &resolve_label))
+        .collect_vec();
+    if !subs.is_empty() {
+        bytecode.push(ret());
+        for sub in &subs {
+            let Gtch::Sub { children, .. } = sub else {
+                unreachable!()
+            };
+            bytecode.extend(
+                children
+                    .iter()
+                    .map(|gtch| assemble_op(gtch, &resolve, &resolve_label)),
+            );
+            bytecode.push(ret());
+        }
+    }
+
+    let (bytecode, op_errs): (Vec<Vec<u8>>, Vec<AssembleError>) =
+        bytecode.into_iter().partition_result();
+    errs.extend(op_errs);
     if !errs.is_empty() {
         return Err(errs
             .into_iter()
@@ -84,9 +78,264 @@ pub fn assemble<'a>(
     Ok(bytecode)
 }
 
+/// The number of bytes `gtch` assembles to. No op's size depends on an address, so placeholder addresses will do.
+pub(crate) fn op_len(gtch: &Gtch) -> usize {
+    assemble_op(gtch, &|_| Some(0), &|_| Some(0)).map_or(0, |b| b.len())
+}
+
+/// Record the address of each label in `ops`, which start from `addr`. Returns the address after the last op.
+fn lay_out<'a>(
+    ops: impl IntoIterator<Item = &'a Gtch>,
+    mut addr: usize,
+    labels: &mut HashMap<&'a str, usize>,
+    errs: &mut Vec<AssembleError>,
+) -> usize {
+    for gtch in ops {
+        if let Gtch::Label(name) = gtch {
+            if labels.insert(name.as_str(), addr).is_some() {
+                errs.push(AssembleError::DuplicateLabel(name.clone()));
+            }
+        }
+        addr += op_len(gtch);
+    }
+    addr
+}
+
+/// Assemble a single op, looking up subroutine addresses with `resolve` and label addresses with `resolve_label`
+fn assemble_op(
+    gtch: &Gtch,
+    resolve: &dyn Fn(&str) -> Option<usize>,
+    resolve_label: &dyn Fn(&str) -> Option<usize>,
+) -> Result<Vec<u8>, AssembleError> {
+    match gtch {
+        Gtch::Copy(i, j) => {
+            let j = index(j, "second argument to Copy")?;
+            Ok(match i {
+                Atom::Idx(i) => vec![Opcode::Copy as u8, *i as u8, j as u8],
+                Atom::Range(r) => {
+                    if r.is_empty() {
+                        return Err(AssembleError::Invalid("Range must be nonempty"));
+                    }
+                    if r.len() + j > 255 {
+                        return Err(AssembleError::Invalid(
+                            "Range ends beyond the max index (255)",
+                        ));
+                    }
+
+                    r.clone()
+                        .enumerate()
+                        .flat_map(|(i, k)| vec![Opcode::Copy as u8, k as u8, j as u8 + i as u8])
+                        .collect_vec()
+                }
+                Atom::PC => vec![Opcode::CopyFromSelf as u8, j as u8],
+                Atom::Relative(offset) => {
+                    let offset = i8::try_from(*offset).map_err(|_| {
+                        AssembleError::Invalid("Offset from `i` is beyond the max (±127)")
+                    })?;
+                    vec![Opcode::CopyRelative as u8, offset as u8, j as u8]
+                }
+                Atom::Label(_) => return Err(AssembleError::Invalid("Only Jump can take a label")),
+                Atom::Expr(_) | Atom::ExprRange(_, _) | Atom::Step(_, _) => {
+                    return Err(AssembleError::Invalid(
+                        "Names must be resolved by compiling",
+                    ))
+                }
+            })
+        }
+        Gtch::Jump(i) => {
+            // A number is wrapped to a chunk index as it always has been, while a label is an exact address
+            let (opcode, addr) = match i {
+                Atom::Idx(i) => (Opcode::Jump, *i),
+                Atom::Label(name) => (
+                    Opcode::JumpAddress,
+                    resolve_label(name)
+                        .ok_or_else(|| AssembleError::UndefinedLabel(name.clone()))?,
+                ),
+                // A jump table, which takes the next address in the range on each run of the bytecode. See
+                // `Op::JumpTable` for how often that is in the plugin.
+                Atom::Range(r) => {
+                    let r = checked_range(r)?;
+                    if r.len() > 255 {
+                        return Err(AssembleError::Invalid(
+                            "Jump table is longer than the max (255)",
+                        ));
+                    }
+                    return Ok(vec![
+                        Opcode::JumpTable as u8,
+                        r.start as u8,
+                        r.len() as u8,
+                        0,
+                    ]);
+                }
+                _ => {
+                    return Err(AssembleError::Invalid(
+                        "Jump takes an address, a label or a range",
+                    ))
+                }
+            };
+            if addr > 255 {
+                return Err(AssembleError::Invalid(
+                    "Jump target is beyond the max address (255)",
+                ));
+            }
+            Ok(vec![opcode as u8, addr as u8])
+        }
+        Gtch::Label(_) | Gtch::Comment { .. } => Ok(vec![]),
+        Gtch::Sample(i) => {
+            // Each chunk of a range is sampled in turn
+            let r = match i {
+                Atom::Idx(i) => *i..*i + 1,
+                Atom::Range(r) => checked_range(r)?,
+                i => return Err(bad_argument(i, "argument to Sample")),
+            };
+            Ok(r.flat_map(|i| [Opcode::Sample as u8, i as u8])
+                .collect_vec())
+        }
+        Gtch::Swap(i, j) => {
+            // Swap two blocks chunk by chunk, where an index is the start of a block as long as the range
+            let (i, j) = match (i, j) {
+                (Atom::Idx(i), Atom::Idx(j)) => (*i..*i + 1, *j..*j + 1),
+                (Atom::Range(r), Atom::Idx(j)) => (r.clone(), *j..*j + r.len()),
+                (Atom::Idx(i), Atom::Range(r)) => (*i..*i + r.len(), r.clone()),
+                (Atom::Range(i), Atom::Range(j)) => {
+                    if i.len() != j.len() {
+                        return Err(AssembleError::Invalid(
+                            "Swapped ranges must be the same length",
+                        ));
+                    }
+                    (i.clone(), j.clone())
+                }
+                (i, j) => {
+                    let bad = if matches!(i, Atom::Idx(_) | Atom::Range(_)) {
+                        j
+                    } else {
+                        i
+                    };
+                    return Err(bad_argument(bad, "argument to Swap"));
+                }
+            };
+            let (i, j) = (checked_range(&i)?, checked_range(&j)?);
+            if i.len() > 1 && i.start < j.end && j.start < i.end {
+                return Err(AssembleError::Invalid("Swapped ranges must not overlap"));
+            }
+            Ok(i.zip(j)
+                .flat_map(|(i, j)| [Opcode::Swap as u8, i as u8, j as u8])
+                .collect_vec())
+        }
+        Gtch::Reverse(i) => {
+            let i = index(i, "argument to Reverse")?;
+            Ok(vec![Opcode::Reverse as u8, i as u8])
+        }
+        Gtch::Random(i) => {
+            let i = index(i, "argument to Random")?;
+            Ok(vec![Opcode::Random as u8, i as u8])
+        }
+        Gtch::HalfSpeed(i, j) | Gtch::DoubleSpeed(i, j) => {
+            let opcode = if gtch.is_half_speed() {
+                Opcode::HalfSpeed
+            } else {
+                Opcode::DoubleSpeed
+            };
+            let (i, j) = (
+                index(i, "argument to a stretch")?,
+                index(j, "argument to a stretch")?,
+            );
+            Ok(vec![opcode as u8, i as u8, j as u8])
+        }
+        Gtch::Mix(mix, i, j) => {
+            let opcode = match mix {
+                Mix::Add => Opcode::Add,
+                Mix::Multiply => Opcode::Multiply,
+                Mix::Average => Opcode::Average,
+                Mix::Max => Opcode::Max,
+            };
+            let (i, j) = (
+                index(i, "argument to a mix")?,
+                index(j, "argument to a mix")?,
+            );
+            Ok(vec![opcode as u8, i as u8, j as u8])
+        }
+        Gtch::Crush(i, amount) | Gtch::Decimate(i, amount) => {
+            let opcode = if gtch.is_crush() {
+                Opcode::Crush
+            } else {
+                Opcode::Decimate
+            };
+            let i = index(i, "argument to Crush or Decimate")?;
+            let amount = u8::try_from(*amount)
+                .map_err(|_| AssembleError::Invalid("Amount is beyond the max (255)"))?;
+            Ok(vec![opcode as u8, i as u8, amount])
+        }
+        Gtch::Io(slot, addr) => {
+            let addr = index(addr, "argument to an I/O read")?;
+            let addr = u8::try_from(addr)
+                .map_err(|_| AssembleError::Invalid("Address is beyond the max (255)"))?;
+            Ok(vec![Opcode::Io as u8, *slot as u8, addr])
+        }
+        Gtch::Call(name) => {
+            let addr =
+                resolve(name).ok_or_else(|| AssembleError::UndefinedSubroutine(name.clone()))?;
+            Ok(vec![Opcode::Call as u8, addr as u8])
+        }
+        Gtch::Sub { .. } => Err(AssembleError::Invalid(
+            "Subroutines must be defined at the top level",
+        )),
+        _ => unreachable!(),
+    }
+}
+
+/// A plain index, which is all most arguments take. Only Copy's first argument takes `i`.
+fn index(atom: &Atom, argument: &'static str) -> Result<usize, AssembleError> {
+    match atom {
+        Atom::Idx(i) => Ok(*i),
+        atom => Err(bad_argument(atom, argument)),
+    }
+}
+
+/// The error for an atom which can't be used as `argument`
+fn bad_argument(atom: &Atom, argument: &'static str) -> AssembleError {
+    let what = match atom {
+        Atom::Idx(_) => "An index",
+        Atom::Range(_) => "A range",
+        Atom::PC | Atom::Relative(_) => "`i`",
+        Atom::Label(_) => "A label",
+        Atom::Expr(_) | Atom::ExprRange(_, _) | Atom::Step(_, _) => {
+            return AssembleError::Invalid("Names must be resolved by compiling")
+        }
+    };
+    AssembleError::Argument(what, argument)
+}
+
+/// A range which is nonempty and fits in a byte
+fn checked_range(r: &Range<usize>) -> Result<Range<usize>, AssembleError> {
+    if r.is_empty() {
+        return Err(AssembleError::Invalid("Range must be nonempty"));
+    }
+    if r.end > 256 {
+        return Err(AssembleError::Invalid(
+            "Range ends beyond the max index (255)",
+        ));
+    }
+    Ok(r.clone())
+}
+
 #[derive(Debug, Error)]
-#[error("{0}")]
-struct AssembleError(&'static str);
+pub(crate) enum AssembleError {
+    #[error("{0}")]
+    Invalid(&'static str),
+    #[error("{0} cannot be used as {1}")]
+    Argument(&'static str, &'static str),
+    #[error("Undefined subroutine `{0}`")]
+    UndefinedSubroutine(String),
+    #[error("Subroutine `{0}` is defined more than once")]
+    DuplicateSubroutine(String),
+    #[error("Undefined label `{0}`")]
+    UndefinedLabel(String),
+    #[error("Label `{0}` is defined more than once")]
+    DuplicateLabel(String),
+    #[error("{0} is not supported by the processor engine, only the classic one")]
+    Unsupported(&'static str),
+}
 
 #[cfg(test)]
 mod tests {
@@ -148,29 +397,127 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_subroutines_are_laid_out_after_main() {
+        let code = vec![
+            Gtch::Sub {
+                name: "sub".to_string(),
+                children: vec![Gtch::Jump(Atom::Idx(1))],
+            },
+            Gtch::Call("sub".to_string()),
+        ];
+        let bytecode = super::assemble(&code, 8).unwrap();
+        let (call, ret, jump) = (Opcode::Call as u8, Opcode::Return as u8, Opcode::Jump as u8);
+        assert_eq!(bytecode, vec![call, 3, ret, jump, 1, ret, 0, 0]);
+    }
+
+    #[test]
+    fn test_labels_resolve_to_byte_offsets() {
+        let code = crate::parse::parse("0>1 top: ~2 .top sub s { inner: .inner } @s").unwrap();
+        let bytecode = super::assemble(&code, 16).unwrap();
+        let (copy, sample) = (Opcode::Copy as u8, Opcode::Sample as u8);
+        let jump = Opcode::JumpAddress as u8;
+        let (call, ret) = (Opcode::Call as u8, Opcode::Return as u8);
+        assert_eq!(
+            bytecode[..13],
+            [copy, 0, 1, sample, 2, jump, 3, call, 10, ret, jump, 10, ret]
+        );
+    }
+
+    #[test]
+    fn test_undefined_and_duplicate_labels() {
+        let undefined = crate::parse::parse(".nowhere").unwrap();
+        assert!(super::assemble(&undefined, 8).is_err());
+        let duplicate = crate::parse::parse("a: 0>1 a: .a").unwrap();
+        assert!(super::assemble(&duplicate, 8).is_err());
+    }
+
+    #[test]
+    fn test_unsupported_arguments_are_named() {
+        let error = |program: &str| {
+            let code = crate::compile::lower(&crate::parse::parse(program).unwrap()).unwrap();
+            super::assemble_op(&code[0], &|_| None, &|_| None)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(error("<i"), "`i` cannot be used as argument to Reverse");
+        assert_eq!(error("i+1+>2"), "`i` cannot be used as argument to a mix");
+        assert_eq!(error("0-2<>i"), "`i` cannot be used as argument to Swap");
+        assert_eq!(
+            error("?0-2"),
+            "A range cannot be used as argument to Random"
+        );
+        assert_eq!(error("~i-1"), "`i` cannot be used as argument to Sample");
+        assert_eq!(
+            error("0>i"),
+            "`i` cannot be used as second argument to Copy"
+        );
+    }
+
+    #[test]
+    fn test_undefined_subroutine() {
+        let code = vec![Gtch::Call("nope".to_string())];
+        assert!(super::assemble(&code, 8).is_err());
+    }
+
     proptest! {
         #[test]
-        #[ignore = "some cases where instructions are duplicated :s"]
         fn test_copy_range(r in arb_range(255), i in 0..255usize) {
-            let gtch = Gtch::Copy(r, Atom::Idx(i));
-            let Ok(result) = super::assemble(once(&gtch), 512) else {return Err(TestCaseError::reject("skipping bad inputs"))};
-            let chunks = result.iter().copied().chunks(3);
-            for chunk in &chunks {
-                let chunk = chunk.collect_vec();
-                if chunk[0] == 0 {
-                    break;
-                }
-                prop_assert_eq!(chunk[0], Opcode::Copy as u8);
-            }
-            let froms = result.iter().skip(1).step_by(3);
-            for (i, j) in froms.tuple_windows() {
-                prop_assert_eq!(j - i, 1, "{},{} should be consecutive", i, j);
-            }
-            let tos = result.iter().skip(2).step_by(3);
-            for (i, j) in tos.tuple_windows() {
-                prop_assert_eq!(j - i, 1, "{},{} should be consecutive", i, j);
+            let gtch = Gtch::Copy(r.clone(), Atom::Idx(i));
+            let r = r.range().unwrap();
+            let Ok(result) = super::assemble(once(&gtch), 1024) else {
+                prop_assert!(r.is_empty() || r.len() + i > 255);
+                return Ok(());
+            };
+            let ops = result.chunks(3).take_while(|op| op[0] != 0).collect_vec();
+            prop_assert_eq!(ops.len(), r.len());
+            for (n, op) in ops.into_iter().enumerate() {
+                prop_assert_eq!(op, [Opcode::Copy as u8, (r.start + n) as u8, (i + n) as u8]);
             }
+        }
+
+        #[test]
+        fn test_sample_range(r in arb_range(255)) {
+            let gtch = Gtch::Sample(r.clone());
+            let r = r.range().unwrap();
+            let Ok(result) = super::assemble(once(&gtch), 512) else {
+                prop_assert!(r.is_empty());
+                return Ok(());
+            };
+            let expected = r.flat_map(|i| [Opcode::Sample as u8, i as u8]).collect_vec();
+            prop_assert_eq!(&result[..expected.len()], expected);
+        }
 
+        #[test]
+        fn test_swap_ranges(r in arb_range(128), j in 0..255usize) {
+            let gtch = Gtch::Swap(r.clone(), Atom::Idx(j));
+            let r = r.range().unwrap();
+            let Ok(result) = super::assemble(once(&gtch), 512) else {
+                let overlaps = r.len() > 1 && r.start < j + r.len() && j < r.end;
+                prop_assert!(r.is_empty() || j + r.len() > 256 || overlaps);
+                return Ok(());
+            };
+            let ops = result.chunks(3).take_while(|op| op[0] != 0).collect_vec();
+            prop_assert_eq!(ops.len(), r.len());
+            for (n, op) in ops.into_iter().enumerate() {
+                prop_assert_eq!(op, [Opcode::Swap as u8, (r.start + n) as u8, (j + n) as u8]);
+            }
         }
     }
+
+    #[test]
+    fn test_ranges_of_swaps_and_jumps() {
+        let (swap, jump_table) = (Opcode::Swap as u8, Opcode::JumpTable as u8);
+        let code = crate::parse::parse("0-2<>4-6 .8-11").unwrap();
+        let bytecode = super::assemble(&code, 12).unwrap();
+        assert_eq!(
+            bytecode,
+            [swap, 0, 4, swap, 1, 5, jump_table, 8, 3, 0, 0, 0]
+        );
+
+        let mismatched = crate::parse::parse("0-2<>4-7").unwrap();
+        assert!(super::assemble(&mismatched, 12).is_err());
+        let overlapping = crate::parse::parse("0-4<>2").unwrap();
+        assert!(super::assemble(&overlapping, 12).is_err());
+    }
 }
@@ -0,0 +1,385 @@
+use color_eyre::Section;
+use itertools::Itertools;
+use processor::instruction::{Instruction, Reg};
+use tracing::instrument;
+use vm::{op::Mix, REGISTER_COUNT};
+
+use crate::{
+    assemble::AssembleError,
+    parse::{Atom, Gtch},
+};
+
+// Register allocation for the generated loops
+const PTR_A: Reg = 0;
+const PTR_B: Reg = 1;
+const VALUE: Reg = 2;
+const SCRATCH: Reg = 3;
+
+/// Processor memory holds audio as little-endian i16 samples
+const BYTES_PER_SAMPLE: u16 = 2;
+
+/// Assemble for the `processor` crate's engine, where `code_size` bytes of code are followed by `data_len` bytes of audio in the same memory.
+///
+/// Chunks are laid over the data region the same way the classic VM lays them over its audio buffer, rounded down to
+/// whole samples. Reverses and mixes work on whole samples, while copies and swaps move their raw bytes.
+///
+/// Only part of the language has a lowering: copies (including from ranges), swaps, reverses, the `+>`, `*>` and `&>` mixes, jumps to raw
+/// addresses and samples, each on plain chunk indices. Stretching, `?`, `!`, `/`, `$` reads, `^>`, labels and
+/// subroutines are rejected, so programs using them only run on the classic engine.
+#[instrument(skip(gtch, code_size, data_len))]
+pub fn assemble_processor<'a>(
+    gtch: impl IntoIterator<Item = &'a Gtch>,
+    code_size: usize,
+    data_len: usize,
+) -> Result<Vec<u8>, eyre::Report> {
+    if code_size + data_len > u16::MAX as usize + 1 {
+        return Err(eyre::eyre!(
+            "Processor memory is too large to address with 16 bits"
+        ));
+    }
+    let chunk_size = (data_len / REGISTER_COUNT) as u16 / BYTES_PER_SAMPLE * BYTES_PER_SAMPLE;
+    let chunk_start = |i: usize| (code_size + (i % REGISTER_COUNT) * chunk_size as usize) as u16;
+
+    let mut code = vec![];
+    let mut errs = vec![];
+    for gtch in gtch {
+        if let Err(err) = assemble_op(gtch, &mut code, chunk_start, chunk_size) {
+            errs.push(err);
+        }
+    }
+    if !errs.is_empty() {
+        return Err(errs
+            .into_iter()
+            .fold(eyre::eyre!("Assembly errors"), |report, err| {
+                report.error(err)
+            }));
+    }
+    Instruction::Halt.encode(&mut code);
+    if code.len() > code_size {
+        return Err(eyre::eyre!(
+            "Program is {} bytes, too long for the processor's {} byte code region",
+            code.len(),
+            code_size
+        ));
+    }
+
+    Ok(code.into_iter().pad_using(code_size, |_| 0).collect_vec())
+}
+
+fn assemble_op(
+    gtch: &Gtch,
+    code: &mut Vec<u8>,
+    chunk_start: impl Fn(usize) -> u16,
+    chunk_size: u16,
+) -> Result<(), AssembleError> {
+    let idx = |atom: &Atom| {
+        atom.clone()
+            .idx()
+            .ok_or(AssembleError::Unsupported("A range or `i` argument"))
+    };
+    let emit = |code: &mut Vec<u8>, instructions: &[Instruction]| {
+        instructions.iter().for_each(|i| i.encode(code))
+    };
+
+    match gtch {
+        // Ops on empty chunks have nothing to do
+        Gtch::Copy(..) | Gtch::Swap(..) | Gtch::Mix(..) if chunk_size == 0 => {}
+        Gtch::Reverse(_) if chunk_size < 2 * BYTES_PER_SAMPLE => {}
+        // A chunk at a time, as the classic VM copies ranges
+        Gtch::Copy(Atom::Range(r), j) => {
+            let j = idx(j)?;
+            for (n, i) in r.clone().enumerate() {
+                copy(code, chunk_start(i), chunk_start(j + n), chunk_size);
+            }
+        }
+        Gtch::Copy(i, j) => copy(code, chunk_start(idx(i)?), chunk_start(idx(j)?), chunk_size),
+        Gtch::Swap(i, j) => {
+            let (a, b) = (chunk_start(idx(i)?), chunk_start(idx(j)?));
+            emit(
+                code,
+                &[Instruction::Li(PTR_A, a), Instruction::Li(PTR_B, b)],
+            );
+            let start = code.len() as u16;
+            emit(
+                code,
+                &[
+                    Instruction::Ld(VALUE, PTR_A),
+                    Instruction::Ld(SCRATCH, PTR_B),
+                    Instruction::St(SCRATCH, PTR_A),
+                    Instruction::St(VALUE, PTR_B),
+                    Instruction::Addi(PTR_A, 1),
+                    Instruction::Addi(PTR_B, 1),
+                ],
+            );
+            loop_until(code, start, a as u32 + chunk_size as u32);
+        }
+        Gtch::Reverse(i) => {
+            let front = chunk_start(idx(i)?);
+            let back = front + (chunk_size - BYTES_PER_SAMPLE);
+            emit(
+                code,
+                &[Instruction::Li(PTR_A, front), Instruction::Li(PTR_B, back)],
+            );
+            let start = code.len() as u16;
+            // Swap a sample a byte at a time, then step the back pointer to the sample before
+            for back_step in [1, 1 - 2 * BYTES_PER_SAMPLE as i8] {
+                emit(
+                    code,
+                    &[
+                        Instruction::Ld(VALUE, PTR_A),
+                        Instruction::Ld(SCRATCH, PTR_B),
+                        Instruction::St(SCRATCH, PTR_A),
+                        Instruction::St(VALUE, PTR_B),
+                        Instruction::Addi(PTR_A, 1),
+                        Instruction::Addi(PTR_B, back_step),
+                    ],
+                );
+            }
+            let samples = chunk_size / BYTES_PER_SAMPLE;
+            loop_until(
+                code,
+                start,
+                front as u32 + (samples / 2 * BYTES_PER_SAMPLE) as u32,
+            );
+        }
+        Gtch::Mix(Mix::Max, _, _) => return Err(AssembleError::Unsupported("Mixing with `^>`")),
+        Gtch::Mix(Mix::Add, i, j) => {
+            let (from, to) = (chunk_start(idx(i)?), chunk_start(idx(j)?));
+            emit(
+                code,
+                &[Instruction::Li(PTR_A, from), Instruction::Li(PTR_B, to)],
+            );
+            let start = code.len() as u16;
+            // Add the low bytes, keeping the carry out of them as 0 or 1 in [VALUE]
+            emit(
+                code,
+                &[
+                    Instruction::Ld(VALUE, PTR_A),
+                    Instruction::Ld(SCRATCH, PTR_B),
+                    Instruction::Add(VALUE, SCRATCH),
+                    Instruction::St(VALUE, PTR_B),
+                    Instruction::Li(SCRATCH, 0x100),
+                    Instruction::And(VALUE, SCRATCH),
+                ],
+            );
+            let set_carry = Instruction::Li(VALUE, 1);
+            let carry = code.len() + Instruction::Jnz(VALUE, 0).size() + Instruction::Jmp(0).size();
+            emit(
+                code,
+                &[
+                    Instruction::Jnz(VALUE, carry as u16),
+                    Instruction::Jmp((carry + set_carry.size()) as u16),
+                    set_carry,
+                ],
+            );
+            // Then add the high bytes onto the carry
+            emit(
+                code,
+                &[
+                    Instruction::Addi(PTR_A, 1),
+                    Instruction::Addi(PTR_B, 1),
+                    Instruction::Ld(SCRATCH, PTR_A),
+                    Instruction::Add(VALUE, SCRATCH),
+                    Instruction::Ld(SCRATCH, PTR_B),
+                    Instruction::Add(VALUE, SCRATCH),
+                    Instruction::St(VALUE, PTR_B),
+                    Instruction::Addi(PTR_A, 1),
+                    Instruction::Addi(PTR_B, 1),
+                ],
+            );
+            loop_until(code, start, from as u32 + chunk_size as u32);
+        }
+        Gtch::Mix(mix, i, j) => {
+            // Bitwise, like the classic VM's bytecode mixes, so a byte at a time is a sample at a time
+            let combine = match mix {
+                Mix::Multiply => Instruction::Xor(VALUE, SCRATCH),
+                Mix::Average => Instruction::And(VALUE, SCRATCH),
+                Mix::Add | Mix::Max => unreachable!(),
+            };
+            let (from, to) = (chunk_start(idx(i)?), chunk_start(idx(j)?));
+            emit(
+                code,
+                &[Instruction::Li(PTR_A, from), Instruction::Li(PTR_B, to)],
+            );
+            let start = code.len() as u16;
+            emit(
+                code,
+                &[
+                    Instruction::Ld(VALUE, PTR_A),
+                    Instruction::Ld(SCRATCH, PTR_B),
+                    combine,
+                    Instruction::St(VALUE, PTR_B),
+                    Instruction::Addi(PTR_A, 1),
+                    Instruction::Addi(PTR_B, 1),
+                ],
+            );
+            loop_until(code, start, from as u32 + chunk_size as u32);
+        }
+        Gtch::Jump(i) => emit(code, &[Instruction::Jmp(idx(i)? as u16)]),
+        Gtch::Sample(i) => {
+            // Read the high byte of the chunk's first sample over the address this instruction reads from, so the
+            // audio decides where the next sample comes from
+            let address = code.len() as u16 + 2;
+            emit(
+                code,
+                &[
+                    Instruction::Li(PTR_A, chunk_start(idx(i)?).wrapping_add(1)),
+                    Instruction::Ld(VALUE, PTR_A),
+                    Instruction::Li(PTR_A, address),
+                    Instruction::St(VALUE, PTR_A),
+                ],
+            );
+        }
+        Gtch::HalfSpeed(_, _) | Gtch::DoubleSpeed(_, _) => {
+            return Err(AssembleError::Unsupported("Stretching"))
+        }
+        Gtch::Random(_) => return Err(AssembleError::Unsupported("Random")),
+        Gtch::Crush(_, _) => return Err(AssembleError::Unsupported("Crushing")),
+        Gtch::Decimate(_, _) => return Err(AssembleError::Unsupported("Decimation")),
+        Gtch::Io(_, _) => return Err(AssembleError::Unsupported("Reading the I/O page")),
+        Gtch::Sub { .. } | Gtch::Call(_) => return Err(AssembleError::Unsupported("Subroutines")),
+        Gtch::Label(_) => return Err(AssembleError::Unsupported("Labels")),
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Copy a nonempty chunk from `from` to `to`
+fn copy(code: &mut Vec<u8>, from: u16, to: u16, chunk_size: u16) {
+    [Instruction::Li(PTR_A, from), Instruction::Li(PTR_B, to)]
+        .iter()
+        .for_each(|i| i.encode(code));
+    let start = code.len() as u16;
+    [
+        Instruction::Ld(VALUE, PTR_A),
+        Instruction::St(VALUE, PTR_B),
+        Instruction::Addi(PTR_A, 1),
+        Instruction::Addi(PTR_B, 1),
+    ]
+    .iter()
+    .for_each(|i| i.encode(code));
+    loop_until(code, start, from as u32 + chunk_size as u32);
+}
+
+/// Jump back to `start` until [PTR_A] reaches `end`. The loop body always runs once, so it must step [PTR_A] up
+/// towards a nonempty `end`, landing on it exactly.
+///
+/// Registers are 32 bits wide and [PTR_A] isn't wrapped as it steps, so `end` is compared in full. A chunk ending at
+/// the top of the 16-bit address space ends at 0x10000, which [Instruction::Li] can't load, so it's loaded one short
+/// and stepped up.
+fn loop_until(code: &mut Vec<u8>, start: u16, end: u32) {
+    debug_assert!((1..=1 << 16).contains(&end));
+    [
+        Instruction::Li(SCRATCH, (end - 1) as u16),
+        Instruction::Addi(SCRATCH, 1),
+        Instruction::Sub(SCRATCH, PTR_A),
+        Instruction::Jnz(SCRATCH, start),
+    ]
+    .iter()
+    .for_each(|i| i.encode(code));
+}
+
+#[cfg(test)]
+mod tests {
+    use processor::{Processor, VmProcessor};
+
+    use crate::parse::parse;
+
+    use super::assemble_processor;
+
+    const CODE_SIZE: usize = 128;
+
+    fn run(program: &str, data: &[u8]) -> Vec<u8> {
+        let gtch = parse(program).unwrap();
+        let mut memory = assemble_processor(&gtch, CODE_SIZE, data.len()).unwrap();
+        memory.extend(data);
+        let mut processor = VmProcessor::new(CODE_SIZE as u16, 1 << 16);
+        processor.run(&mut memory);
+        memory.split_off(CODE_SIZE)
+    }
+
+    #[test]
+    fn test_copy() {
+        let data = (0..32).collect::<Vec<u8>>();
+        let result = run("0>3", &data);
+        assert_eq!(&result[6..8], &[0, 1]);
+        assert_eq!(&result[..6], &data[..6]);
+        assert_eq!(&result[8..], &data[8..]);
+        let result = run("1-3>5", &data);
+        assert_eq!(&result[10..14], &data[2..6]);
+    }
+
+    #[test]
+    fn test_swap() {
+        let data = (0..32).collect::<Vec<u8>>();
+        let result = run("1<>2", &data);
+        assert_eq!(&result[2..6], &[4, 5, 2, 3]);
+    }
+
+    fn to_samples(bytes: &[u8]) -> Vec<i16> {
+        let samples = bytes.chunks_exact(2);
+        samples.map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+    }
+
+    fn from_samples(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_reverse() {
+        // Chunks of three samples, reversed a sample at a time
+        let samples = (0..48).map(|n| n * 300 - 7000).collect::<Vec<i16>>();
+        let result = to_samples(&run("<1", &from_samples(&samples)));
+        assert_eq!(&result[3..6], &[-5500, -5800, -6100]);
+        assert_eq!(&result[..3], &samples[..3]);
+        assert_eq!(&result[6..], &samples[6..]);
+    }
+
+    #[test]
+    fn test_mix() {
+        let data = (0..32).collect::<Vec<u8>>();
+        let result = run("1+>2 1*>3", &data);
+        assert_eq!(&result[4..6], &[2 + 4, 3 + 5]);
+        assert_eq!(&result[6..8], &[2 ^ 6, 3 ^ 7]);
+        assert_eq!(&result[2..4], &data[2..4]);
+        // Adding carries from the low byte into the high one, and wraps
+        let samples = [0x00ff, 0x7fff, -2, 0x0001, 0x0001, 3].repeat(8);
+        let result = to_samples(&run("0+>1", &from_samples(&samples)));
+        assert_eq!(&result[3..6], &[0x0100, -0x8000, 1]);
+        assert_eq!(&result[..3], &samples[..3]);
+    }
+
+    #[test]
+    fn test_tiny_chunks() {
+        // Chunks of one sample, which reversing leaves alone
+        let data = (0..32).collect::<Vec<u8>>();
+        let result = run("<1 0>3 1<>2", &data);
+        assert_eq!(&result[..8], &[0, 1, 4, 5, 2, 3, 0, 1]);
+        // Chunks of less than a sample, which nothing touches
+        assert_eq!(run("<1 0>3 1<>2 1+>2", &data[..16]), &data[..16]);
+    }
+
+    #[test]
+    fn test_chunk_at_the_top_of_memory() {
+        let data_len = (1 << 16) - CODE_SIZE;
+        let data = (0..data_len).map(|n| n as u8).collect::<Vec<u8>>();
+        let chunk_size = data_len / 16;
+        let gtch = parse("0>15 <15").unwrap();
+        let code = assemble_processor(&gtch, CODE_SIZE, data_len).unwrap();
+        let mut memory = code.clone();
+        memory.extend(&data);
+        let mut processor = VmProcessor::new(CODE_SIZE as u16, 1 << 20);
+        processor.run(&mut memory);
+        // The loops stop at the end of memory rather than wrapping into the code
+        assert_eq!(&memory[..CODE_SIZE], &code);
+        let last = &memory[CODE_SIZE + 15 * chunk_size..];
+        let samples = last.chunks_exact(2).rev();
+        assert!(samples.eq(data[..chunk_size].chunks_exact(2)));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let gtch = parse("?1").unwrap();
+        assert!(assemble_processor(&gtch, CODE_SIZE, 32).is_err());
+    }
+}
@@ -1,60 +1,538 @@
-use std::iter::once;
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    ops::Range,
+    rc::Rc,
+};
 
-use itertools::Itertools;
+use color_eyre::Section;
+use thiserror::Error;
 use tracing::instrument;
-use vm::op::Op;
 
-use crate::{assemble::assemble, parse::Gtch};
+use crate::{
+    assemble::assemble,
+    assemble_processor::assemble_processor,
+    optimize::optimize,
+    parse::{Atom, BinOp, Expr, Gtch},
+};
+
+/// The engine to compile for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Target {
+    /// Bytecode for the classic [vm]
+    #[default]
+    Vm,
+    /// Code for the `processor` crate's engine, which shares its memory with `data_len` bytes of audio
+    Processor { data_len: usize },
+}
+
+/// How to compile a program
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompileOptions {
+    pub target: Target,
+    /// Remove ops which can't change the audio with [optimize]. Off keeps every op of the unrolled program.
+    pub optimize: bool,
+}
 
 #[instrument(skip(ast, bytecode_len))]
 pub fn compile(ast: &[Gtch], bytecode_len: usize) -> Result<Vec<u8>, eyre::Report> {
+    compile_for(ast, bytecode_len, CompileOptions::default())
+}
+
+#[instrument(skip(ast, bytecode_len))]
+pub fn compile_for(
+    ast: &[Gtch],
+    bytecode_len: usize,
+    options: CompileOptions,
+) -> Result<Vec<u8>, eyre::Report> {
+    let mut ir = lower(ast)?;
+    if options.optimize {
+        ir = optimize(ir);
+    }
+    match options.target {
+        Target::Vm => assemble(&ir, bytecode_len),
+        Target::Processor { data_len } => assemble_processor(&ir, bytecode_len, data_len),
+    }
+}
+
+/// How deep macros can expand inside one another, which stops a macro calling itself forever
+const MAX_EXPANSION_DEPTH: usize = 16;
+
+/// How many macro expansions a whole program can take, which stops a macro calling itself more than once from
+/// taking exponentially many to reach [MAX_EXPANSION_DEPTH]
+const MAX_EXPANSIONS: usize = 1024;
+
+/// How many ops a repeat group can unroll to. Far more than fit in any bytecode, so only runaway counts reach it.
+const MAX_UNROLLED_LEN: usize = 1 << 16;
+
+/// Resolve names, expand macros and unroll repeat groups, at the top level and in subroutine bodies
+pub(crate) fn lower(ast: &[Gtch]) -> Result<Vec<Gtch>, eyre::Report> {
+    let mut errs = vec![];
+    let mut ir = lower_block(ast, &mut Scope::default(), &mut errs);
+    strip_steps(&mut ir);
+    if !errs.is_empty() {
+        return Err(errs
+            .into_iter()
+            .fold(eyre::eyre!("Compile errors"), |report, err| {
+                report.error(err)
+            }));
+    }
+    Ok(ir)
+}
+
+fn lower_block(ast: &[Gtch], scope: &mut Scope, errs: &mut Vec<CompileError>) -> Vec<Gtch> {
     let mut ir = vec![];
+    scope.enter();
 
     for node in ast.iter().cloned() {
-        if let Gtch::RepeatGroup {
-            max_iters,
-            children,
-        } = node
-        {
-            ir.extend(unroll_repeat_group(max_iters, children));
-        } else {
-            ir.push(node);
+        match node {
+            Gtch::Comment { .. } => {}
+            Gtch::Let { name, value } => {
+                if let Some(value) = scope.eval_index(&value, errs) {
+                    scope.bind(name, value, errs);
+                }
+            }
+            Gtch::RepeatGroup {
+                max_iters,
+                stride,
+                children,
+            } => {
+                let children = lower_block(&children, scope, errs);
+                let max_iters = scope.eval_index(&max_iters, errs).unwrap_or(0);
+                ir.extend(unroll_repeat_group(max_iters, stride, &children, errs))
+            }
+            Gtch::Sub { name, children } => ir.push(Gtch::Sub {
+                name,
+                children: lower_block(&children, scope, errs),
+            }),
+            Gtch::Def {
+                name,
+                params,
+                children,
+            } => scope.define(name, params, children, errs),
+            Gtch::Expand { name, args, span } => {
+                let mut expansion_errs = vec![];
+                ir.extend(scope.expand(&name, &args, &mut expansion_errs));
+                errs.extend(
+                    expansion_errs
+                        .into_iter()
+                        .map(|err| CompileError::InExpansion {
+                            name: name.clone(),
+                            span: span.clone(),
+                            err: Box::new(err),
+                        }),
+                );
+            }
+            mut node => {
+                // A bound name is jumped to as its number, not as a label
+                if let Gtch::Jump(Atom::Label(name)) = &node {
+                    if let Some(addr) = scope.get(name) {
+                        node = Gtch::Jump(Atom::Idx(addr));
+                    }
+                }
+                for atom in node.atoms_mut() {
+                    scope.resolve(atom, errs);
+                }
+                ir.push(node)
+            }
         }
     }
 
-    println!("{:?}", ir);
+    scope.exit();
+    ir
+}
 
-    assemble(&ir, bytecode_len)
+/// The names bound by `let` and the macros defined by `def` in a block
+#[derive(Clone, Default)]
+struct Block {
+    values: HashMap<String, usize>,
+    macros: HashMap<String, Rc<Macro>>,
 }
 
-/// Unroll a repeated group statically, incrementing any arguments of the child ops
-#[allow(clippy::option_map_unit_fn)]
-fn unroll_repeat_group(repeats: usize, children: Vec<Gtch>) -> impl Iterator<Item = Gtch> {
-    let len = children.len();
-    children
-        .into_iter()
-        .cycle()
-        .enumerate()
-        .map(move |(op_idx, mut node)| {
-            let group_idx = op_idx / len;
+/// A macro's body, along with everything that was in scope where it was defined
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Gtch>,
+    env: Block,
+}
 
-            node.copy_mut().map(|(i, j)| {
-                i.idx_mut().map(|i| *i += group_idx);
-                j.idx_mut().map(|j| *j += group_idx);
-            });
-            node.swap_mut().map(|(i, j)| {
-                i.idx_mut().map(|i| *i += group_idx);
-                j.idx_mut().map(|j| *j += group_idx);
-            });
-            node.jump_mut().map(|i| {
-                i.idx_mut().map(|i| *i += group_idx);
-            });
-            node.sample_mut().map(|i| {
-                i.idx_mut().map(|i| *i += group_idx);
+/// The blocks being lowered, innermost last
+#[derive(Default)]
+struct Scope {
+    blocks: Vec<Block>,
+    /// How many macro expansions deep this scope is
+    depth: usize,
+    /// Counts every expansion, to give each its own labels
+    expansions: Rc<Cell<usize>>,
+}
+
+impl Scope {
+    fn enter(&mut self) {
+        self.blocks.push(Block::default());
+    }
+
+    fn exit(&mut self) {
+        self.blocks.pop();
+    }
+
+    fn innermost(&mut self) -> &mut Block {
+        self.blocks.last_mut().expect("bound outside of a block")
+    }
+
+    fn get(&self, name: &str) -> Option<usize> {
+        self.blocks
+            .iter()
+            .rev()
+            .find_map(|block| block.values.get(name).copied())
+    }
+
+    fn get_macro(&self, name: &str) -> Option<Rc<Macro>> {
+        self.blocks
+            .iter()
+            .rev()
+            .find_map(|block| block.macros.get(name).cloned())
+    }
+
+    /// Bind `name` in the innermost block. Names can't be rebound, even from an inner block.
+    fn bind(&mut self, name: String, value: usize, errs: &mut Vec<CompileError>) {
+        if self.get(&name).is_some() {
+            errs.push(CompileError::Shadowed(name));
+            return;
+        }
+        self.innermost().values.insert(name, value);
+    }
+
+    /// Define a macro in the innermost block. Like names, macros can't be redefined.
+    fn define(
+        &mut self,
+        name: String,
+        params: Vec<String>,
+        body: Vec<Gtch>,
+        errs: &mut Vec<CompileError>,
+    ) {
+        if self.get_macro(&name).is_some() {
+            errs.push(CompileError::Shadowed(name));
+            return;
+        }
+        let mut env = Block::default();
+        for block in &self.blocks {
+            env.values.extend(block.values.clone());
+            env.macros.extend(block.macros.clone());
+        }
+        let mac = Macro { params, body, env };
+        self.innermost().macros.insert(name, Rc::new(mac));
+    }
+
+    /// Expand a macro into lowered ops. The arguments are worked out here, but the body only sees them and what
+    /// was in scope where it was defined, and its labels are renamed so that each expansion gets its own.
+    fn expand(&self, name: &str, args: &[Expr], errs: &mut Vec<CompileError>) -> Vec<Gtch> {
+        let Some(mac) = self.get_macro(name) else {
+            errs.push(CompileError::UndefinedMacro(name.to_string()));
+            return vec![];
+        };
+        if args.len() != mac.params.len() {
+            errs.push(CompileError::ArgCount {
+                expected: mac.params.len(),
+                found: args.len(),
             });
-            node
-        })
-        .take(len * repeats)
+            return vec![];
+        }
+        if self.depth == MAX_EXPANSION_DEPTH {
+            errs.push(CompileError::TooDeep);
+            return vec![];
+        }
+        // Counted before the body expands, so the limit cuts off the whole tree of expansions. Only the first
+        // expansion over it is reported.
+        let expansion = self.expansions.get();
+        if expansion <= MAX_EXPANSIONS {
+            self.expansions.set(expansion + 1);
+        }
+        if expansion >= MAX_EXPANSIONS {
+            if expansion == MAX_EXPANSIONS {
+                errs.push(CompileError::TooManyExpansions);
+            }
+            return vec![];
+        }
+        let args = args
+            .iter()
+            .map(|arg| self.eval_index(arg, errs))
+            .collect::<Vec<_>>();
+
+        // The macro can see itself, so it can at least hit the depth limit when it calls itself
+        let mut env = mac.env.clone();
+        env.macros.insert(name.to_string(), mac.clone());
+        let mut inner = Scope {
+            blocks: vec![env],
+            depth: self.depth + 1,
+            expansions: self.expansions.clone(),
+        };
+        // Parameters may shadow names from where the macro was defined, just not each other
+        inner.enter();
+        for (param, arg) in mac.params.iter().zip(args) {
+            if inner.innermost().values.contains_key(param) {
+                errs.push(CompileError::Shadowed(param.clone()));
+            }
+            inner
+                .innermost()
+                .values
+                .insert(param.clone(), arg.unwrap_or(0));
+        }
+        let mut body = lower_block(&mac.body, &mut inner, errs);
+
+        let labels = body
+            .iter()
+            .filter_map(|node| match node {
+                Gtch::Label(label) => Some(label.clone()),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        for node in &mut body {
+            if let Gtch::Label(label) | Gtch::Jump(Atom::Label(label)) = node {
+                if labels.contains(label) {
+                    // `#` starts a comment, so this can't clash with a label in the source
+                    *label = format!("{label}#{expansion}");
+                }
+            }
+        }
+        body
+    }
+
+    fn eval(&self, expr: &Expr, errs: &mut Vec<CompileError>) -> Option<Value> {
+        match expr {
+            Expr::Num(n) => Some(Value::Const(*n as isize)),
+            Expr::Name(name) => {
+                let value = self.get(name);
+                if value.is_none() {
+                    errs.push(CompileError::Undefined(name.clone()));
+                }
+                value.map(|value| Value::Const(value as isize))
+            }
+            Expr::PC => Some(Value::Relative(0)),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (self.eval(a, errs), self.eval(b, errs));
+                let value = match (op, a?, b?) {
+                    (BinOp::Add, Value::Const(a), Value::Const(b)) => {
+                        a.checked_add(b).map(Value::Const)
+                    }
+                    (BinOp::Add, Value::Relative(a), Value::Const(b))
+                    | (BinOp::Add, Value::Const(b), Value::Relative(a)) => {
+                        a.checked_add(b).map(Value::Relative)
+                    }
+                    (BinOp::Sub, Value::Const(a), Value::Const(b)) => {
+                        a.checked_sub(b).map(Value::Const)
+                    }
+                    (BinOp::Sub, Value::Relative(a), Value::Const(b)) => {
+                        a.checked_sub(b).map(Value::Relative)
+                    }
+                    (BinOp::Sub, Value::Relative(a), Value::Relative(b)) => {
+                        a.checked_sub(b).map(Value::Const)
+                    }
+                    (BinOp::Mul, Value::Const(a), Value::Const(b)) => {
+                        a.checked_mul(b).map(Value::Const)
+                    }
+                    (BinOp::Rem, Value::Const(a), Value::Const(b)) => {
+                        a.checked_rem_euclid(b).map(Value::Const)
+                    }
+                    _ => {
+                        errs.push(CompileError::NotAnOffset);
+                        return None;
+                    }
+                };
+                if value.is_none() {
+                    errs.push(CompileError::Overflow);
+                }
+                value
+            }
+        }
+    }
+
+    /// Evaluate an expression which has to be a plain number, like a repeat count
+    fn eval_index(&self, expr: &Expr, errs: &mut Vec<CompileError>) -> Option<usize> {
+        match self.eval(expr, errs)? {
+            Value::Const(n) => index(n, errs),
+            Value::Relative(_) => {
+                errs.push(CompileError::UsesPC);
+                None
+            }
+        }
+    }
+
+    /// Replace an [Atom::Expr] or [Atom::ExprRange] with the index or range it works out to
+    fn resolve(&self, atom: &mut Atom, errs: &mut Vec<CompileError>) {
+        match atom {
+            Atom::Expr(i) => match self.eval(i, errs) {
+                Some(Value::Const(i)) => {
+                    if let Some(i) = index(i, errs) {
+                        *atom = Atom::Idx(i);
+                    }
+                }
+                Some(Value::Relative(0)) => *atom = Atom::PC,
+                Some(Value::Relative(offset)) => *atom = Atom::Relative(offset),
+                None => {}
+            },
+            Atom::ExprRange(start, end) => {
+                if let (Some(start), Some(end)) =
+                    (self.eval_index(start, errs), self.eval_index(end, errs))
+                {
+                    *atom = Atom::Range(start..end);
+                }
+            }
+            Atom::Step(inner, _) => self.resolve(inner, errs),
+            _ => {}
+        }
+    }
+}
+
+/// What an expression works out to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Value {
+    Const(isize),
+    /// An offset from the PC, which is only known when running
+    Relative(isize),
+}
+
+fn index(n: isize, errs: &mut Vec<CompileError>) -> Option<usize> {
+    let index = usize::try_from(n).ok();
+    if index.is_none() {
+        errs.push(CompileError::Negative(n));
+    }
+    index
+}
+
+#[derive(Debug, Error)]
+enum CompileError {
+    #[error("`{0}` is not defined")]
+    Undefined(String),
+    #[error("`{0}` is already defined, names can't be shadowed")]
+    Shadowed(String),
+    #[error("`i` can only have a number added to or taken away from it")]
+    NotAnOffset,
+    #[error("`i` can't be used here, only as an index")]
+    UsesPC,
+    #[error("Indices can't be negative, this one is {0}")]
+    Negative(isize),
+    #[error("Expression overflows, or takes the remainder of dividing by 0")]
+    Overflow,
+    #[error("Undefined macro `{0}`")]
+    UndefinedMacro(String),
+    #[error("Expected {expected} arguments, found {found}")]
+    ArgCount { expected: usize, found: usize },
+    #[error("Macros expand more than {MAX_EXPANSION_DEPTH} deep")]
+    TooDeep,
+    #[error("Macros expand more than {MAX_EXPANSIONS} times")]
+    TooManyExpansions,
+    #[error("Repeat group unrolls to more than {MAX_UNROLLED_LEN} ops")]
+    TooLong,
+    #[error("Label `{0}` is inside a repeat group, which would define it on every pass")]
+    RepeatedLabel(String),
+    #[error("In `{name}` called at {span:?}: {err}")]
+    InExpansion {
+        name: String,
+        span: Range<usize>,
+        err: Box<CompileError>,
+    },
+}
+
+/// Unroll a repeated group statically, moving any arguments of the child ops along by `stride` on each pass.
+///
+/// Expressions have already been worked out by now. Those relative to the PC are left alone unless given a step,
+/// as they move along with the copies of their op anyway.
+fn unroll_repeat_group(
+    repeats: usize,
+    stride: isize,
+    children: &[Gtch],
+    errs: &mut Vec<CompileError>,
+) -> Vec<Gtch> {
+    let Some(len) = children
+        .len()
+        .checked_mul(repeats)
+        .filter(|len| *len <= MAX_UNROLLED_LEN)
+    else {
+        errs.push(CompileError::TooLong);
+        return vec![];
+    };
+    // Each pass would define the label again, so they're reported here where the cause is clear
+    if repeats > 1 {
+        for child in children {
+            if let Gtch::Label(label) = child {
+                // Without the suffix a macro expansion gave it
+                let label = label.split('#').next().unwrap_or(label);
+                errs.push(CompileError::RepeatedLabel(label.to_string()));
+            }
+        }
+    }
+    let mut ir = Vec::with_capacity(len);
+    for pass in 0..repeats as isize {
+        for child in children {
+            if repeats > 1 && child.is_label() {
+                continue;
+            }
+            let mut node = child.clone();
+            // Only chunks and addresses move along, amounts stay put
+            for atom in node.atoms_mut() {
+                advance(atom, stride, pass, errs);
+            }
+            ir.push(node);
+        }
+    }
+    ir
+}
+
+/// Move an atom along for a pass of a repeat group. A stepped atom keeps its step for any outer groups.
+fn advance(atom: &mut Atom, stride: isize, pass: isize, errs: &mut Vec<CompileError>) {
+    let step = match atom {
+        Atom::Step(_, step) => *step,
+        _ => stride,
+    };
+    let Some(by) = step.checked_mul(pass) else {
+        errs.push(CompileError::Overflow);
+        return;
+    };
+    match atom {
+        Atom::Idx(i) => shift(i, by, errs),
+        Atom::Step(inner, _) => match inner.as_mut() {
+            Atom::Idx(i) => shift(i, by, errs),
+            Atom::Range(r) => {
+                shift(&mut r.start, by, errs);
+                shift(&mut r.end, by, errs);
+            }
+            inner @ (Atom::PC | Atom::Relative(_)) => {
+                let offset = inner.clone().relative().unwrap_or(0);
+                match offset.checked_add(by) {
+                    Some(0) => *inner = Atom::PC,
+                    Some(offset) => *inner = Atom::Relative(offset),
+                    None => errs.push(CompileError::Overflow),
+                }
+            }
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+fn shift(i: &mut usize, by: isize, errs: &mut Vec<CompileError>) {
+    match (*i as isize).checked_add(by) {
+        Some(n) => {
+            if let Some(n) = index(n, errs) {
+                *i = n;
+            }
+        }
+        None => errs.push(CompileError::Overflow),
+    }
+}
+
+/// Unwrap the [Atom::Step]s once every repeat group has been unrolled
+fn strip_steps(ir: &mut [Gtch]) {
+    for node in ir {
+        if let Gtch::Sub { children, .. } = node {
+            strip_steps(children);
+        }
+        for atom in node.atoms_mut() {
+            if let Atom::Step(inner, _) = atom {
+                *atom = std::mem::replace(inner.as_mut(), Atom::PC);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -67,11 +545,184 @@ mod tests {
     proptest! {
         #[test]
         fn test_unrolling(ops in prop::collection::vec(prop::sample::select(&[
-            "~0", "0>1", "0<>1", ".0"
+            "~0", "0>1", "0<>1", ".0", "<0", "0_>1", "0>>1", "?0", "0+>1", "0*>1", "0&>1", "0^>1", "0!4", "0/4", "$beat>0", "i+1>0", "~0-2", ".4-8", "0-2<>4"
         ]), 0..10).prop_map(|ops| ops.join(" "))) {
             let program = ["[0", &ops, "]"].join(" ");
             let result = parse::parse(&program).unwrap();
             compile(&result, 32).unwrap();
         }
     }
+
+    #[test]
+    fn test_subroutine_groups_are_unrolled() {
+        let result = parse::parse("sub ladder { [4 0>1] } @ladder").unwrap();
+        let ir = lower(&result).unwrap();
+        let Gtch::Sub { children, .. } = &ir[0] else {
+            panic!("expected a subroutine, got {:?}", ir[0]);
+        };
+        assert_eq!(children.len(), 4);
+    }
+
+    #[test]
+    fn test_bindings_are_resolved() {
+        let result = parse::parse("let n = 2 let from = 1 [n from>n] 0-n>3").unwrap();
+        let ir = lower(&result).unwrap();
+        assert!(matches!(ir[0], Gtch::Copy(Atom::Idx(1), Atom::Idx(2))));
+        assert!(matches!(ir[1], Gtch::Copy(Atom::Idx(2), Atom::Idx(3))));
+        assert!(matches!(&ir[2], Gtch::Copy(Atom::Range(r), Atom::Idx(3)) if *r == (0..2)));
+    }
+
+    #[test]
+    fn test_bindings_are_scoped_to_their_block() {
+        let inner = parse::parse("[1 let n = 2 n>3] n>3").unwrap();
+        assert!(lower(&inner).is_err());
+        let shadowed = parse::parse("let n = 2 [1 let n = 3]").unwrap();
+        assert!(lower(&shadowed).is_err());
+        let undefined = parse::parse("[n 0>1]").unwrap();
+        assert!(lower(&undefined).is_err());
+    }
+
+    #[test]
+    fn test_expressions_are_evaluated() {
+        let result =
+            parse::parse("let k = 3 let a = 15 i+2>k*2 (a+1)%16>k pc-1>0 i+1-i>0 [2 i+1>0]")
+                .unwrap();
+        let ir = lower(&result).unwrap();
+        assert!(matches!(ir[0], Gtch::Copy(Atom::Relative(2), Atom::Idx(6))));
+        assert!(matches!(ir[1], Gtch::Copy(Atom::Idx(0), Atom::Idx(3))));
+        assert!(matches!(
+            ir[2],
+            Gtch::Copy(Atom::Relative(-1), Atom::Idx(0))
+        ));
+        assert!(matches!(ir[3], Gtch::Copy(Atom::Idx(1), Atom::Idx(0))));
+        assert!(matches!(ir[4], Gtch::Copy(Atom::Relative(1), Atom::Idx(0))));
+        assert!(matches!(ir[5], Gtch::Copy(Atom::Relative(1), Atom::Idx(1))));
+    }
+
+    #[test]
+    fn test_bad_expressions() {
+        for program in [
+            "i*2>0",
+            "0-(1+i)>1",
+            "let n = i",
+            "[i 0>1]",
+            "(0-1)>1",
+            "(1%0)>1",
+        ] {
+            let result = parse::parse(program).unwrap();
+            assert!(lower(&result).is_err(), "{program} should not compile");
+        }
+    }
+
+    #[test]
+    fn test_macros_are_expanded() {
+        let result = parse::parse(
+            "let a = 1
+            def stutter(a, n) { [n a>a+1] }
+            def twice(x) { stutter(x, 2) stutter(x+4, 2) }
+            twice(a)",
+        )
+        .unwrap();
+        let ir = lower(&result).unwrap();
+        let copies = ir
+            .iter()
+            .map(|node| match node {
+                Gtch::Copy(Atom::Idx(i), Atom::Idx(j)) => (*i, *j),
+                node => panic!("expected a copy, got {node:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(copies, vec![(1, 2), (2, 3), (5, 6), (6, 7)]);
+    }
+
+    #[test]
+    fn test_macros_are_hygienic() {
+        // The body can't see the caller's names, its lets stay inside it, and each expansion gets its own labels
+        let caller = parse::parse("def f() { n>0 } let n = 1 f()").unwrap();
+        assert!(lower(&caller).is_err());
+        let leaky = parse::parse("def f() { let n = 1 } f() n>0").unwrap();
+        assert!(lower(&leaky).is_err());
+        let looped = parse::parse("def spin() { top: .top } spin() spin()").unwrap();
+        compile(&lower(&looped).unwrap(), 16).unwrap();
+    }
+
+    #[test]
+    fn test_macro_errors_point_at_the_call() {
+        let result = parse::parse("def f(a) { a>b } 0>1 f(2)").unwrap();
+        let mut errs = vec![];
+        lower_block(&result, &mut Scope::default(), &mut errs);
+        assert!(
+            matches!(&errs[..], [CompileError::InExpansion { name, span, .. }] if name == "f" && *span == (21..25))
+        );
+        let recursive = parse::parse("def f() { f() } f()").unwrap();
+        assert!(lower(&recursive).is_err());
+        // Would take 4^16 expansions to reach the depth limit
+        let branching = parse::parse("def f() { 0>1 f() f() f() f() } f()").unwrap();
+        let mut errs = vec![];
+        lower_block(&branching, &mut Scope::default(), &mut errs);
+        let too_many = "Macros expand more than 1024 times";
+        assert!(errs.iter().any(|err| err.to_string().ends_with(too_many)));
+        let arity = parse::parse("def f(a) { } f(1, 2)").unwrap();
+        assert!(lower(&arity).is_err());
+    }
+
+    #[test]
+    fn test_groups_take_strides_and_steps() {
+        let copies = |program| {
+            lower(&parse::parse(program).unwrap())
+                .unwrap()
+                .into_iter()
+                .map(|op| match op {
+                    Gtch::Copy(Atom::Idx(i), Atom::Idx(j))
+                    | Gtch::Mix(_, Atom::Idx(i), Atom::Idx(j)) => (i, j),
+                    op => panic!("expected a copy, got {op:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(copies("[3 +2 0>1]"), [(0, 1), (2, 3), (4, 5)]);
+        assert_eq!(copies("[4 0>7:-1]"), [(0, 7), (1, 6), (2, 5), (3, 4)]);
+        assert_eq!(copies("[3 -1 4>0:0]"), [(4, 0), (3, 0), (2, 0)]);
+        assert_eq!(copies("[3 0+>6:0]"), [(0, 6), (1, 6), (2, 6)]);
+        assert_eq!(
+            copies("[2 +4 [2 0:1>1:3]]"),
+            [(0, 1), (1, 4), (1, 4), (2, 7)]
+        );
+
+        let result = lower(&parse::parse("[2 i:1>0]").unwrap()).unwrap();
+        assert!(matches!(result[0], Gtch::Copy(Atom::PC, Atom::Idx(0))));
+        assert!(matches!(
+            result[1],
+            Gtch::Copy(Atom::Relative(1), Atom::Idx(1))
+        ));
+
+        assert!(lower(&parse::parse("[2 -1 0>1]").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_nested_groups_are_unrolled() {
+        let result = parse::parse("[2 [2 0>1]]").unwrap();
+        assert_eq!(lower(&result).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_runaway_groups_are_errors() {
+        let result = parse::parse("let n = 255 [n [n [n 0>1]]]").unwrap();
+        assert!(lower(&result).is_err());
+    }
+
+    #[test]
+    fn test_labels_cant_be_repeated() {
+        let errors = |program| {
+            let mut errs = vec![];
+            lower_block(
+                &parse::parse(program).unwrap(),
+                &mut Scope::default(),
+                &mut errs,
+            );
+            errs.iter().map(ToString::to_string).collect::<Vec<_>>()
+        };
+        let message = "Label `top` is inside a repeat group, which would define it on every pass";
+        assert_eq!(errors("[2 top: 0>1] .top"), [message]);
+        assert!(errors("def f() { top: .top } [2 f()]")[0].ends_with(message));
+        assert!(errors("[1 top: .top]").is_empty());
+    }
 }
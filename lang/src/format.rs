@@ -0,0 +1,340 @@
+use chumsky::error::Rich;
+use itertools::Itertools;
+use tracing::instrument;
+use vm::op::Mix;
+
+use crate::parse::{parse, Atom, BinOp, Expr, Gtch};
+
+const INDENT: &str = "  ";
+
+/// Parse a program and print it back in canonical form.
+///
+/// Ops are separated by single spaces, with each group, subroutine, macro and binding on its own line and labels
+/// starting a new one. Groups with nothing but ops inside stay on one line, others put each child on its own
+/// indented line. Comments keep their text and whether they had a line to themselves or followed some code.
+#[instrument(skip(src))]
+pub fn format(src: &str) -> Result<String, Vec<Rich<'_, char>>> {
+    let gtch = parse(src)?;
+    Ok(print(&gtch, src))
+}
+
+/// Print a program parsed from `src` in canonical form, as [format] does. The program may have been changed since,
+/// as long as its comments weren't. Without any source to go by, each comment gets a line of its own.
+pub fn print(gtch: &[Gtch], src: &str) -> String {
+    // Spans are into the trimmed source, as for [parse]
+    block(gtch, src.trim()).join("\n")
+}
+
+/// Lay out a block's nodes as lines, without indentation
+fn block(nodes: &[Gtch], src: &str) -> Vec<String> {
+    let mut lines = vec![];
+    let mut current: Option<String> = None;
+    for node in nodes {
+        match node {
+            Gtch::Comment { text, span } if !has_own_line(src, span.start) => {
+                match current.take() {
+                    Some(line) => lines.push(format!("{line} #{text}")),
+                    None => match lines.last_mut() {
+                        Some(line) if !ends_in_comment(line) => *line += &format!(" #{text}"),
+                        _ => lines.push(format!("#{text}")),
+                    },
+                }
+            }
+            Gtch::Comment { text, .. } => {
+                lines.extend(current.take());
+                lines.push(format!("#{text}"));
+            }
+            Gtch::Label(name) => {
+                lines.extend(current.take());
+                current = Some(format!("{name}:"));
+            }
+            Gtch::Let { name, value } => {
+                lines.extend(current.take());
+                lines.push(format!("let {name} = {}", expr(value, Level::Sum)));
+            }
+            Gtch::RepeatGroup {
+                max_iters,
+                stride,
+                children,
+            } => {
+                lines.extend(current.take());
+                let header = match stride {
+                    1 => format!("[{}", expr(max_iters, Level::Sum)),
+                    stride => format!("[{} {stride:+}", expr(max_iters, Level::Sum)),
+                };
+                lines.extend(group(header, children, "]", src));
+            }
+            Gtch::Sub { name, children } => {
+                lines.extend(current.take());
+                lines.extend(group(format!("sub {name} {{"), children, "}", src));
+            }
+            Gtch::Def {
+                name,
+                params,
+                children,
+            } => {
+                lines.extend(current.take());
+                let header = format!("def {name}({}) {{", params.join(", "));
+                lines.extend(group(header, children, "}", src));
+            }
+            op => {
+                let op = self::op(op);
+                current = Some(match current.take() {
+                    Some(line) => format!("{line} {op}"),
+                    None => op,
+                });
+            }
+        }
+    }
+    lines.extend(current);
+    lines
+}
+
+/// Lay out a group, on one line if it only holds ops
+fn group(header: String, children: &[Gtch], closer: &str, src: &str) -> Vec<String> {
+    let simple = children.iter().all(|child| {
+        !matches!(
+            child,
+            Gtch::Comment { .. }
+                | Gtch::Let { .. }
+                | Gtch::Label(_)
+                | Gtch::RepeatGroup { .. }
+                | Gtch::Sub { .. }
+                | Gtch::Def { .. }
+        )
+    });
+    if simple {
+        let ops = children.iter().map(op).join(" ");
+        // Brackets hug their contents, braces are spaced apart
+        let line = match (ops.is_empty(), closer) {
+            (true, "]") => format!("{header}]"),
+            (true, _) => format!("{header}{closer}"),
+            (false, "]") => format!("{header} {ops}]"),
+            (false, _) => format!("{header} {ops} {closer}"),
+        };
+        return vec![line];
+    }
+
+    // A comment straight after the header stays on its line
+    let mut header = header;
+    let mut children = children;
+    if let Some((Gtch::Comment { text, span }, rest)) = children.split_first() {
+        if !has_own_line(src, span.start) {
+            header += &format!(" #{text}");
+            children = rest;
+        }
+    }
+    let mut lines = vec![header];
+    lines.extend(
+        block(children, src)
+            .into_iter()
+            .map(|line| format!("{INDENT}{line}")),
+    );
+    lines.push(closer.to_string());
+    lines
+}
+
+/// Whether nothing but whitespace comes before `start` on its line, or `src` is too short to tell
+fn has_own_line(src: &str, start: usize) -> bool {
+    src.get(..start).is_none_or(|before| {
+        before
+            .rsplit('\n')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .is_empty()
+    })
+}
+
+/// Whether a laid out line ends in a comment, which would swallow anything after it
+fn ends_in_comment(line: &str) -> bool {
+    line.contains('#')
+}
+
+fn op(gtch: &Gtch) -> String {
+    match gtch {
+        Gtch::Copy(i, j) => format!("{}>{}", lead(i), atom(j)),
+        Gtch::Jump(i) => format!(".{}", target(i)),
+        Gtch::Sample(i) => format!("~{}", atom(i)),
+        Gtch::Swap(i, j) => format!("{}<>{}", lead(i), atom(j)),
+        Gtch::Reverse(i) => format!("<{}", atom(i)),
+        Gtch::HalfSpeed(i, j) => format!("{}_>{}", lead(i), atom(j)),
+        Gtch::DoubleSpeed(i, j) => format!("{}>>{}", lead(i), atom(j)),
+        Gtch::Random(i) => format!("?{}", atom(i)),
+        Gtch::Mix(mix, i, j) => {
+            let mix = match mix {
+                Mix::Add => "+>",
+                Mix::Multiply => "*>",
+                Mix::Average => "&>",
+                Mix::Max => "^>",
+            };
+            format!("{}{mix}{}", lead(i), atom(j))
+        }
+        Gtch::Crush(i, amount) => format!("{}!{amount}", lead(i)),
+        Gtch::Decimate(i, amount) => format!("{}/{amount}", lead(i)),
+        Gtch::Io(slot, i) => format!("${}>{}", slot.name(), atom(i)),
+        Gtch::Call(name) => format!("@{name}"),
+        Gtch::Expand { name, args, .. } => format!(
+            "{name}({})",
+            args.iter().map(|arg| expr(arg, Level::Sum)).join(", ")
+        ),
+        // Laid out on lines of their own by [block] and [group]
+        Gtch::RepeatGroup { .. }
+        | Gtch::Sub { .. }
+        | Gtch::Def { .. }
+        | Gtch::Let { .. }
+        | Gtch::Label(_)
+        | Gtch::Comment { .. } => unreachable!(),
+    }
+}
+
+/// The atom an op starts with, where a bare name with a step would read as a label
+fn lead(atom: &Atom) -> String {
+    match atom {
+        Atom::Step(name, step) if matches!(**name, Atom::Expr(Expr::Name(_))) => {
+            format!("({}):{step}", self::atom(name))
+        }
+        atom => self::atom(atom),
+    }
+}
+
+/// The atom a jump takes, where a name would read as a label even with more of an expression after it
+fn target(atom: &Atom) -> String {
+    match atom {
+        Atom::Expr(Expr::Num(_) | Expr::PC) => self::atom(atom),
+        Atom::Expr(x) => format!("({})", expr(x, Level::Sum)),
+        Atom::ExprRange(x, y) => format!("({})-{}", expr(x, Level::Sum), expr(y, Level::Value)),
+        Atom::Step(atom, step) => format!("{}:{step}", target(atom)),
+        atom => self::atom(atom),
+    }
+}
+
+fn atom(atom: &Atom) -> String {
+    match atom {
+        Atom::Idx(i) => i.to_string(),
+        Atom::Range(r) => format!("{}-{}", r.start, r.end),
+        Atom::PC => "i".to_string(),
+        Atom::Relative(offset) => format!("i{offset:+}"),
+        // Outside of brackets `-` makes a range, unless it's taking from the PC
+        Atom::Expr(Expr::Binary(BinOp::Sub, x, y)) if x.uses_pc() => {
+            format!("{}-{}", expr(x, Level::Value), expr(y, Level::Value))
+        }
+        Atom::Expr(x) => expr(x, Level::Value),
+        Atom::ExprRange(x, y) => format!("{}-{}", expr(x, Level::Value), expr(y, Level::Value)),
+        Atom::Label(name) => name.clone(),
+        Atom::Step(atom, step) => format!("{}:{step}", self::atom(atom)),
+    }
+}
+
+/// How tightly an expression is bound by where it's printed, loosest first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Level {
+    /// Anywhere `-` is fine
+    Sum,
+    /// An atom's index, where `-` would make a range
+    Value,
+    Product,
+    Term,
+}
+
+fn expr(x: &Expr, level: Level) -> String {
+    match x {
+        Expr::Num(n) => n.to_string(),
+        Expr::Name(name) => name.clone(),
+        Expr::PC => "i".to_string(),
+        Expr::Binary(op, a, b) => {
+            // The loosest level the op can be printed at bare, and the levels of its operands. Ops are left
+            // associative, so the right operand binds tighter.
+            let (sym, loosest, left, right) = match op {
+                BinOp::Add => ("+", Level::Value, Level::Sum, Level::Product),
+                BinOp::Sub => ("-", Level::Sum, Level::Sum, Level::Product),
+                BinOp::Mul => ("*", Level::Product, Level::Product, Level::Term),
+                BinOp::Rem => ("%", Level::Product, Level::Product, Level::Term),
+            };
+            if level > loosest {
+                return format!("({})", expr(x, Level::Sum));
+            }
+            format!("{}{sym}{}", expr(a, left.max(level)), expr(b, right))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::format;
+
+    #[test]
+    fn test_formatting_spacing_and_groups() {
+        let formatted = format(
+            "  0>1    1>2
+            loop:   [4  +2 0>1   2<>3] .loop
+            let n=2*(3+1)   sub s{ [n 0>n:-1] }
+            def f( a,b ){a>b}  f(1,  2) [2 [2 i-1>0]] [0] sub e {}",
+        )
+        .unwrap();
+        assert_eq!(
+            formatted,
+            "0>1 1>2
+loop:
+[4 +2 0>1 2<>3]
+.loop
+let n = 2*(3+1)
+sub s {
+  [n 0>n:-1]
+}
+def f(a, b) { a>b }
+f(1, 2)
+[2
+  [2 i-1>0]
+]
+[0]
+sub e {}"
+        );
+    }
+
+    #[test]
+    fn test_formatting_keeps_comments() {
+        let formatted = format(
+            "# a stutter
+            loop: 0>1 # copy
+               # on its own
+            [2 # twice
+              1>2
+            ]    # trailing
+            .loop",
+        )
+        .unwrap();
+        assert_eq!(
+            formatted,
+            "# a stutter
+loop: 0>1 # copy
+# on its own
+[2 # twice
+  1>2
+] # trailing
+.loop"
+        );
+    }
+
+    #[test]
+    fn test_formatting_keeps_jumps_to_expressions() {
+        assert_eq!(format("let a = 3\n.(a+1)").unwrap(), "let a = 3\n.(a+1)");
+        assert_eq!(format(".(a) .(a)-4 .(a):1").unwrap(), ".(a) .(a)-4 .(a):1");
+    }
+
+    proptest! {
+        #[test]
+        fn test_formatting_is_idempotent(ops in prop::collection::vec(prop::sample::select(&[
+            "~0", "0>1", "0<>1", ".0", "<0", "0_>1", "0>>1", "?0", "0+>1", "0*>1", "0&>1", "0^>1", "0!4", "0/4",
+            "$beat>0", "i+1>0", "i-(2-1)>0", "0-2<>4", "0:-1>3:2", "(n):1>n:0", "(1+2)*3>0", "l:", ".l", ".(x+1)", ".(x)", ".i+x", ".(x)-2", ".(x):1", "# note\n", "let x = 1-2",
+            "[2 +3", "[", "]", "sub s {", "}", "@s", "f(1, 2*3)", "\n", "   ",
+        ]), 0..24).prop_map(|ops| ops.join(" "))) {
+            // Brackets may not balance, so only programs which parse are checked
+            if let Ok(formatted) = format(&ops) {
+                prop_assert_eq!(format(&formatted).unwrap(), formatted.clone(), "{}", ops);
+            }
+        }
+    }
+}
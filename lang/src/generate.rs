@@ -1,48 +1,259 @@
-use itertools::Itertools;
-use rand::prelude::*;
+use std::ops::Range;
+
+use rand::{distributions::WeightedIndex, prelude::*, rngs::StdRng};
 use tracing::instrument;
+use vm::{io::IoSlot, op::Mix, REGISTER_COUNT};
+
+use crate::{
+    assemble::op_len,
+    format::print,
+    parse::{Atom, Expr, Gtch},
+};
+
+/// The kinds of op [generate] picks between
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpKind {
+    Jump,
+    /// A jump over a range of addresses
+    JumpTable,
+    Sample,
+    Copy,
+    /// A copy from the chunk under the PC
+    CopyFromPC,
+    /// A copy from a chunk a little way on from the PC
+    CopyRelative,
+    Swap,
+    Reverse,
+    Random,
+    HalfSpeed,
+    DoubleSpeed,
+    Mix,
+    Crush,
+    Decimate,
+    Io,
+    RepeatGroup,
+}
+
+impl OpKind {
+    pub const ALL: [OpKind; 16] = [
+        OpKind::Jump,
+        OpKind::JumpTable,
+        OpKind::Sample,
+        OpKind::Copy,
+        OpKind::CopyFromPC,
+        OpKind::CopyRelative,
+        OpKind::Swap,
+        OpKind::Reverse,
+        OpKind::Random,
+        OpKind::HalfSpeed,
+        OpKind::DoubleSpeed,
+        OpKind::Mix,
+        OpKind::Crush,
+        OpKind::Decimate,
+        OpKind::Io,
+        OpKind::RepeatGroup,
+    ];
+}
+
+/// What kind of programs [generate] makes
+#[derive(Clone, Debug)]
+pub struct GeneratorConfig {
+    /// The same config with the same seed always generates the same program
+    pub seed: u64,
+    /// How often each of [OpKind::ALL] is picked relative to the others. A weight of 0 never picks it.
+    pub weights: [u32; OpKind::ALL.len()],
+    /// Where chunk indices are picked from, which must be nonempty. They wrap at [REGISTER_COUNT], so the
+    /// default has one of each.
+    pub indices: Range<usize>,
+    /// The most bytes the program may assemble to, which also bounds jump and I/O addresses
+    pub max_len: usize,
+    /// How many ops each block tries to hold, fewer if they don't fit
+    pub ops: Range<usize>,
+    /// How deep repeat groups may nest, with none at 0
+    pub max_depth: usize,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            weights: [1; OpKind::ALL.len()],
+            indices: 0..REGISTER_COUNT,
+            max_len: 512,
+            ops: 1..9,
+            max_depth: 1,
+        }
+    }
+}
 
-pub fn generate() -> String {
-    gen_children(true)
+impl GeneratorConfig {
+    pub fn with_weight(mut self, kind: OpKind, weight: u32) -> Self {
+        self.weights[kind as usize] = weight;
+        self
+    }
 }
 
+/// Generate a random program, starting with a comment recording its seed
 #[instrument]
-fn gen_children(recurse: bool) -> String {
-    let mut rng = thread_rng();
-    let mut choices: Vec<Box<dyn Fn() -> String>> = vec![
-        Box::new(|| format!(".{}", thread_rng().gen_range(0..256))),
-        Box::new(|| format!("~{}", thread_rng().gen_range(0..256))),
-        Box::new(|| {
-            format!(
-                "{}>{}",
-                thread_rng().gen_range(0..256),
-                thread_rng().gen_range(0..256)
-            )
-        }),
-        Box::new(|| format!("i>{}", thread_rng().gen_range(0..256),)),
-        Box::new(|| {
-            format!(
-                "{}<>{}",
-                thread_rng().gen_range(0..256),
-                thread_rng().gen_range(0..256)
-            )
-        }),
-        Box::new(|| {
-            if recurse {
-                format!(
-                    "[{} {}]",
-                    thread_rng().gen_range(1..256),
-                    gen_children(false)
-                )
-            } else {
-                "".to_string()
+pub fn generate(config: &GeneratorConfig) -> String {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut program = vec![Gtch::Comment {
+        text: format!(" seed {}", config.seed),
+        span: 0..0,
+    }];
+    // Unrolling moves indices on, but they have to stay within a byte
+    let headroom = 256usize.saturating_sub(config.indices.end);
+    program.extend(gen_block(config, &mut rng, config.max_len, 0, headroom).0);
+    print(&program, "")
+}
+
+/// Generate ops assembling to at most `budget` bytes, whose indices can be moved on `headroom` more by the groups
+/// they're in. Returns the ops and the number of bytes they assemble to.
+fn gen_block(
+    config: &GeneratorConfig,
+    rng: &mut StdRng,
+    budget: usize,
+    depth: usize,
+    headroom: usize,
+) -> (Vec<Gtch>, usize) {
+    let Ok(kinds) = WeightedIndex::new(config.weights) else {
+        return (vec![], 0);
+    };
+    let count = if config.ops.is_empty() {
+        0
+    } else {
+        rng.gen_range(config.ops.clone())
+    };
+    let mut block = vec![];
+    let mut len = 0;
+    for _ in 0..count {
+        let kind = OpKind::ALL[kinds.sample(rng)];
+        let Some((op, op_len)) = gen_op(kind, config, rng, budget - len, depth, headroom) else {
+            continue;
+        };
+        if len + op_len <= budget {
+            len += op_len;
+            block.push(op);
+        }
+    }
+    (block, len)
+}
+
+/// Generate an op of the given kind and the number of bytes it assembles to, if there's one that fits
+fn gen_op(
+    kind: OpKind,
+    config: &GeneratorConfig,
+    rng: &mut StdRng,
+    budget: usize,
+    depth: usize,
+    headroom: usize,
+) -> Option<(Gtch, usize)> {
+    let idx = |rng: &mut StdRng| Atom::Idx(rng.gen_range(config.indices.clone()));
+    let addr = |rng: &mut StdRng| rng.gen_range(0..config.max_len.clamp(1, 256));
+    let op = match kind {
+        // Raw addresses would move out of the program as a group unrolls
+        OpKind::Jump | OpKind::Io if depth > 0 => return None,
+        OpKind::Jump => Gtch::Jump(Atom::Idx(addr(rng))),
+        OpKind::JumpTable => {
+            let start = addr(rng);
+            Gtch::Jump(Atom::Range(start..(start + rng.gen_range(2..8)).min(256)))
+        }
+        OpKind::Sample => Gtch::Sample(idx(rng)),
+        OpKind::Copy => Gtch::Copy(idx(rng), idx(rng)),
+        OpKind::CopyFromPC => Gtch::Copy(Atom::PC, idx(rng)),
+        OpKind::CopyRelative => Gtch::Copy(Atom::Relative(rng.gen_range(1..16)), idx(rng)),
+        OpKind::Swap => Gtch::Swap(idx(rng), idx(rng)),
+        OpKind::Reverse => Gtch::Reverse(idx(rng)),
+        OpKind::Random => Gtch::Random(idx(rng)),
+        OpKind::HalfSpeed => Gtch::HalfSpeed(idx(rng), idx(rng)),
+        OpKind::DoubleSpeed => Gtch::DoubleSpeed(idx(rng), idx(rng)),
+        OpKind::Mix => {
+            let mix = *[Mix::Add, Mix::Multiply, Mix::Average, Mix::Max]
+                .choose(rng)
+                .unwrap();
+            Gtch::Mix(mix, idx(rng), idx(rng))
+        }
+        OpKind::Crush => Gtch::Crush(idx(rng), rng.gen_range(0..256)),
+        OpKind::Decimate => Gtch::Decimate(idx(rng), rng.gen_range(0..256)),
+        OpKind::Io => Gtch::Io(*IoSlot::ALL.choose(rng).unwrap(), Atom::Idx(addr(rng))),
+        OpKind::RepeatGroup => {
+            if depth >= config.max_depth {
+                return None;
             }
-        }),
-    ];
-    (1..rng.gen_range(2..10))
-        .map(|i| {
-            let choice = choices.choose_mut(&mut rng).unwrap();
-            choice()
-        })
-        .join(" ")
+            // Pick the count first, so the children can be fitted to it
+            let count = rng.gen_range(1..=(headroom + 1).min(255));
+            let (children, len) = gen_block(
+                config,
+                rng,
+                budget / count,
+                depth + 1,
+                headroom - (count - 1),
+            );
+            let group = Gtch::RepeatGroup {
+                max_iters: Expr::Num(count),
+                stride: 1,
+                children,
+            };
+            return Some((group, count * len));
+        }
+    };
+    let len = op_len(&op);
+    Some((op, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, GeneratorConfig, OpKind};
+    use crate::{assemble::op_len, compile::lower, parse::parse};
+
+    #[test]
+    fn test_generated_programs_are_reproducible() {
+        let config = GeneratorConfig {
+            seed: 7,
+            ..Default::default()
+        };
+        assert_eq!(generate(&config), generate(&config));
+        assert!(generate(&config).starts_with("# seed 7\n"));
+        let other = GeneratorConfig {
+            seed: 8,
+            ..Default::default()
+        };
+        assert_ne!(generate(&config), generate(&other));
+    }
+
+    #[test]
+    fn test_generated_programs_fit_their_config() {
+        for seed in 0..64 {
+            for max_depth in 0..3 {
+                let config = GeneratorConfig {
+                    seed,
+                    max_len: 64,
+                    max_depth,
+                    ..Default::default()
+                };
+                let program = generate(&config);
+                let ir = lower(&parse(&program).unwrap()).unwrap();
+                let len: usize = ir.iter().map(op_len).sum();
+                assert!(len <= config.max_len, "{program} is {len} bytes");
+                crate::compile::compile(&parse(&program).unwrap(), config.max_len).unwrap();
+                if max_depth == 0 {
+                    assert!(!program.contains('['), "{program}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_weights_pick_the_ops() {
+        let config = OpKind::ALL.into_iter().fold(
+            GeneratorConfig {
+                ops: 4..5,
+                ..Default::default()
+            },
+            |config, kind| config.with_weight(kind, 0),
+        );
+        assert_eq!(generate(&config), "# seed 0");
+        let copies = generate(&config.with_weight(OpKind::Copy, 1));
+        assert_eq!(copies.lines().nth(1).unwrap().matches('>').count(), 4);
+    }
 }
@@ -1,6 +1,10 @@
 pub mod assemble;
+pub mod assemble_processor;
 pub mod parse;
 pub use ariadne::*;
 pub use chumsky::error::Rich;
 pub mod compile;
+pub mod format;
 pub mod generate;
+pub mod mutate;
+pub mod optimize;
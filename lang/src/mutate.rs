@@ -0,0 +1,289 @@
+use rand::{prelude::*, rngs::StdRng};
+use tracing::instrument;
+use vm::op::Mix;
+
+use crate::parse::{Atom, Expr, Gtch};
+
+/// The small changes [mutate] makes to a program
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mutation {
+    /// Move one of an op's indices up or down by one
+    NudgeIndex,
+    /// Turn an op into another kind taking the same operands
+    SwapKind,
+    /// Repeat an op or group straight after itself
+    Duplicate,
+    /// Remove an op or group
+    Delete,
+    /// Repeat a group once more or once less
+    ChangeCount,
+}
+
+impl Mutation {
+    pub const ALL: [Mutation; 5] = [
+        Mutation::NudgeIndex,
+        Mutation::SwapKind,
+        Mutation::Duplicate,
+        Mutation::Delete,
+        Mutation::ChangeCount,
+    ];
+}
+
+/// Make one small change to a parsed program, picked by `seed`. Returns the program unchanged if nothing in it can
+/// be changed.
+///
+/// Only ops and repeat groups are changed, wherever they're nested. Labels, bindings, subroutines and macros are
+/// left where they are, so names keep resolving. The program can still stop compiling, say when a nudged index is
+/// moved past the last chunk by a group.
+#[instrument(skip(program))]
+pub fn mutate(program: &[Gtch], seed: u64) -> Vec<Gtch> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut mutations = Mutation::ALL;
+    mutations.shuffle(&mut rng);
+    for mutation in mutations {
+        if let Some(mutated) = apply(program, mutation, &mut rng) {
+            return mutated;
+        }
+    }
+    program.to_vec()
+}
+
+/// Apply a mutation to a random node it fits, if there is one
+pub fn apply(program: &[Gtch], mutation: Mutation, rng: &mut StdRng) -> Option<Vec<Gtch>> {
+    let fits = |gtch: &Gtch| match mutation {
+        Mutation::NudgeIndex => gtch
+            .clone()
+            .atoms_mut()
+            .into_iter()
+            .any(|atom| can_nudge(atom)),
+        Mutation::SwapKind => !kinds(gtch).is_empty(),
+        Mutation::Duplicate | Mutation::Delete => is_op_or_group(gtch),
+        Mutation::ChangeCount => matches!(
+            gtch,
+            Gtch::RepeatGroup {
+                max_iters: Expr::Num(_),
+                ..
+            }
+        ),
+    };
+    let mut paths = vec![];
+    find(program, &mut vec![], &fits, &mut paths);
+    let path = paths.choose(rng)?;
+
+    let mut program = program.to_vec();
+    let (block, i) = locate(&mut program, path);
+    match mutation {
+        Mutation::NudgeIndex => {
+            let by = if rng.gen() { 1 } else { -1 };
+            let mut atoms = block[i].atoms_mut();
+            atoms.retain(|atom| can_nudge(atom));
+            let atom = atoms.choose_mut(rng)?;
+            // Go the other way where one way would leave a byte
+            if !nudge(atom, by) {
+                nudge(atom, -by);
+            }
+        }
+        Mutation::SwapKind => block[i] = kinds(&block[i]).choose(rng)?.clone(),
+        Mutation::Duplicate => block.insert(i, block[i].clone()),
+        Mutation::Delete => {
+            block.remove(i);
+        }
+        Mutation::ChangeCount => {
+            if let Gtch::RepeatGroup {
+                max_iters: Expr::Num(count),
+                ..
+            } = &mut block[i]
+            {
+                *count = match *count {
+                    0 | 1 => *count + 1,
+                    255.. => *count - 1,
+                    _ if rng.gen() => *count + 1,
+                    _ => *count - 1,
+                };
+            }
+        }
+    }
+    Some(program)
+}
+
+fn is_op_or_group(gtch: &Gtch) -> bool {
+    !matches!(
+        gtch,
+        Gtch::Comment { .. }
+            | Gtch::Label(_)
+            | Gtch::Let { .. }
+            | Gtch::Sub { .. }
+            | Gtch::Def { .. }
+    )
+}
+
+/// Collect the path to each node in `block` which `fits`, through the children of groups, subroutines and macros
+fn find(
+    block: &[Gtch],
+    path: &mut Vec<usize>,
+    fits: &dyn Fn(&Gtch) -> bool,
+    paths: &mut Vec<Vec<usize>>,
+) {
+    for (i, gtch) in block.iter().enumerate() {
+        path.push(i);
+        if fits(gtch) {
+            paths.push(path.clone());
+        }
+        if let Gtch::RepeatGroup { children, .. }
+        | Gtch::Sub { children, .. }
+        | Gtch::Def { children, .. } = gtch
+        {
+            find(children, path, fits, paths);
+        }
+        path.pop();
+    }
+}
+
+/// The block a path from [find] ends in, and the node's index in it
+fn locate<'a>(block: &'a mut Vec<Gtch>, path: &[usize]) -> (&'a mut Vec<Gtch>, usize) {
+    match path {
+        [i] => (block, *i),
+        [i, rest @ ..] => match &mut block[*i] {
+            Gtch::RepeatGroup { children, .. }
+            | Gtch::Sub { children, .. }
+            | Gtch::Def { children, .. } => locate(children, rest),
+            _ => unreachable!(),
+        },
+        [] => unreachable!(),
+    }
+}
+
+/// Move an index along by `by`, if it stays within a byte. Indices still to be resolved are left alone.
+fn nudge(atom: &mut Atom, by: isize) -> bool {
+    match atom {
+        Atom::Idx(i) => match i.checked_add_signed(by).filter(|i| *i < 256) {
+            Some(moved) => {
+                *i = moved;
+                true
+            }
+            None => false,
+        },
+        Atom::Range(r) => match (r.start.checked_add_signed(by), r.end.checked_add_signed(by)) {
+            (Some(start), Some(end)) if end <= 256 => {
+                *r = start..end;
+                true
+            }
+            _ => false,
+        },
+        Atom::Relative(offset) => {
+            *offset += by;
+            true
+        }
+        Atom::Step(atom, _) => nudge(atom, by),
+        Atom::PC | Atom::Expr(_) | Atom::ExprRange(_, _) | Atom::Label(_) => false,
+    }
+}
+
+fn can_nudge(atom: &Atom) -> bool {
+    nudge(&mut atom.clone(), 1) || nudge(&mut atom.clone(), -1)
+}
+
+/// The other kinds of op `gtch` could be with the same operands. Only plain chunk indices are carried over, as
+/// not every op takes ranges or the PC.
+fn kinds(gtch: &Gtch) -> Vec<Gtch> {
+    let pair = |i: &Atom, j: &Atom| {
+        let (i, j) = (i.clone(), j.clone());
+        vec![
+            Gtch::Copy(i.clone(), j.clone()),
+            Gtch::Swap(i.clone(), j.clone()),
+            Gtch::HalfSpeed(i.clone(), j.clone()),
+            Gtch::DoubleSpeed(i.clone(), j.clone()),
+            Gtch::Mix(Mix::Add, i.clone(), j.clone()),
+            Gtch::Mix(Mix::Multiply, i.clone(), j.clone()),
+            Gtch::Mix(Mix::Average, i.clone(), j.clone()),
+            Gtch::Mix(Mix::Max, i, j),
+        ]
+    };
+    let single = |i: &Atom| {
+        vec![
+            Gtch::Sample(i.clone()),
+            Gtch::Reverse(i.clone()),
+            Gtch::Random(i.clone()),
+        ]
+    };
+    let all = match gtch {
+        Gtch::Copy(i @ Atom::Idx(_), j @ Atom::Idx(_))
+        | Gtch::Swap(i @ Atom::Idx(_), j @ Atom::Idx(_))
+        | Gtch::HalfSpeed(i @ Atom::Idx(_), j @ Atom::Idx(_))
+        | Gtch::DoubleSpeed(i @ Atom::Idx(_), j @ Atom::Idx(_))
+        | Gtch::Mix(_, i @ Atom::Idx(_), j @ Atom::Idx(_)) => pair(i, j),
+        Gtch::Sample(i @ Atom::Idx(_))
+        | Gtch::Reverse(i @ Atom::Idx(_))
+        | Gtch::Random(i @ Atom::Idx(_)) => single(i),
+        Gtch::Crush(i, amount) | Gtch::Decimate(i, amount) => vec![
+            Gtch::Crush(i.clone(), *amount),
+            Gtch::Decimate(i.clone(), *amount),
+        ],
+        _ => vec![],
+    };
+    all.into_iter().filter(|other| other != gtch).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{apply, mutate, Mutation};
+    use crate::{
+        compile::compile,
+        format::print,
+        generate::{generate, GeneratorConfig},
+        parse::parse,
+    };
+
+    fn mutated(program: &str, mutation: Mutation, seed: u64) -> Option<String> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        apply(&parse(program).unwrap(), mutation, &mut rng).map(|mutated| print(&mutated, program))
+    }
+
+    #[test]
+    fn test_mutations_make_small_changes() {
+        for seed in 0..16 {
+            let nudged = mutated("[2 0>1] l: .l", Mutation::NudgeIndex, seed).unwrap();
+            assert!(
+                ["[2 1>1]\nl: .l", "[2 0>2]\nl: .l", "[2 0>0]\nl: .l"].contains(&nudged.as_str())
+            );
+            // The comment stays on the op's line
+            let swapped = mutated("0>1 # keep", Mutation::SwapKind, seed).unwrap();
+            assert!(
+                !swapped.starts_with("0>1")
+                    && swapped.ends_with(" # keep")
+                    && !swapped.contains('\n'),
+                "{swapped}"
+            );
+            let duplicated = mutated("[2 <0]", Mutation::Duplicate, seed).unwrap();
+            assert!(["[2 <0]\n[2 <0]", "[2 <0 <0]"].contains(&duplicated.as_str()));
+            let deleted = mutated("sub s { 0>1 } @s", Mutation::Delete, seed).unwrap();
+            assert!(["sub s {}\n@s", "sub s { 0>1 }"].contains(&deleted.as_str()));
+            let counted = mutated("[1 [4 0!2]]", Mutation::ChangeCount, seed).unwrap();
+            assert!(["[2\n  [4 0!2]\n]", "[1\n  [3 0!2]\n]", "[1\n  [5 0!2]\n]"]
+                .contains(&counted.as_str()));
+        }
+        // Nothing to change
+        assert!(mutated("l: .l", Mutation::NudgeIndex, 0).is_none());
+        assert!(mutated("0-4>i", Mutation::SwapKind, 0).is_none());
+        assert!(mutated("# note", Mutation::Delete, 0).is_none());
+        assert!(mutated("[n 0>1]", Mutation::ChangeCount, 0).is_none());
+    }
+
+    #[test]
+    fn test_mutated_programs_still_compile() {
+        for seed in 0..64 {
+            let config = GeneratorConfig {
+                seed,
+                max_len: 64,
+                ..Default::default()
+            };
+            let program = parse(&generate(&config)).unwrap();
+            let mutated = mutate(&program, seed);
+            assert_eq!(mutate(&program, seed).len(), mutated.len());
+            // Duplicating can double a group's length
+            compile(&parse(&print(&mutated, "")).unwrap(), 256).unwrap();
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use tracing::instrument;
+use vm::REGISTER_COUNT;
+
+use crate::parse::{Atom, Gtch};
+
+/// Remove ops from compiled IR which can't change the audio in the chunks: copies and swaps of a chunk with itself
+/// or with a chunk already holding the same audio, swaps straight back, and copies overwritten before anything
+/// reads them. Then merge runs of copies between consecutive chunks into range copies.
+///
+/// Chunk indices wrap like the VM's. Each removed op is replaced by a [pad], so nothing moves and the VM plays
+/// exactly the same audio, only without doing the removed ops' work. Self-modification sees the pads' bytes
+/// rather than the removed ops' though, so it can take the program somewhere else. Programs which jump to raw
+/// addresses or depend on the PC are left as they are, as control could arrive at any op.
+#[instrument(skip(ir))]
+pub fn optimize(ir: Vec<Gtch>) -> Vec<Gtch> {
+    if ir.iter().any(depends_on_addresses) {
+        return ir;
+    }
+    optimize_block(ir)
+}
+
+fn optimize_block(mut ir: Vec<Gtch>) -> Vec<Gtch> {
+    for node in &mut ir {
+        if let Gtch::Sub { children, .. } = node {
+            *children = optimize_block(std::mem::take(children));
+        }
+    }
+    // Each pass can open up more for the others
+    loop {
+        let before = ir.clone();
+        ir = drop_redundant(ir);
+        ir = cancel_swaps(ir);
+        ir = drop_dead_writes(ir);
+        if ir == before {
+            break;
+        }
+    }
+    merge_copies(ir)
+}
+
+/// What a removed copy or swap is replaced by: the same op on a single chunk, which changes nothing. It assembles
+/// to as many bytes, takes a step of the VM and costs as much of its budget, so the frames played and where the
+/// run stops stay the same. The audio backend skips the work.
+fn pad(gtch: Gtch) -> Gtch {
+    match gtch {
+        Gtch::Copy(_, j) => Gtch::Copy(j.clone(), j),
+        Gtch::Swap(i, _) => Gtch::Swap(i.clone(), i),
+        gtch => gtch,
+    }
+}
+
+fn is_pad(gtch: &Gtch) -> bool {
+    matches!(effect(gtch), Effect::Copy(i, j) | Effect::Swap(i, j) if i == j)
+}
+
+/// What an op does to the chunks, as far as optimizing goes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Effect {
+    Copy(usize, usize),
+    Swap(usize, usize),
+    /// Changes chunk `i`, reading any chunk
+    Writes(usize),
+    /// Reads any chunk without changing them
+    Reads,
+    /// Anything could have happened, or control may arrive from elsewhere
+    Barrier,
+}
+
+fn chunk(atom: &Atom) -> Option<usize> {
+    match atom {
+        Atom::Idx(i) => Some(i % REGISTER_COUNT),
+        _ => None,
+    }
+}
+
+fn effect(gtch: &Gtch) -> Effect {
+    match gtch {
+        Gtch::Copy(i, j) => match (chunk(i), chunk(j)) {
+            (Some(i), Some(j)) => Effect::Copy(i, j),
+            _ => Effect::Barrier,
+        },
+        Gtch::Swap(i, j) => match (chunk(i), chunk(j)) {
+            (Some(i), Some(j)) => Effect::Swap(i, j),
+            _ => Effect::Barrier,
+        },
+        Gtch::Reverse(i) | Gtch::Random(i) | Gtch::Crush(i, _) | Gtch::Decimate(i, _) => {
+            chunk(i).map_or(Effect::Barrier, Effect::Writes)
+        }
+        Gtch::HalfSpeed(_, j) | Gtch::DoubleSpeed(_, j) | Gtch::Mix(_, _, j) => {
+            chunk(j).map_or(Effect::Barrier, Effect::Writes)
+        }
+        Gtch::Sample(_) => Effect::Reads,
+        _ => Effect::Barrier,
+    }
+}
+
+/// Whether an op jumps to a raw address or works relative to the PC
+fn depends_on_addresses(gtch: &Gtch) -> bool {
+    match gtch {
+        Gtch::Jump(Atom::Idx(_) | Atom::Range(_)) | Gtch::Io(_, _) => true,
+        Gtch::Sub { children, .. } => children.iter().any(depends_on_addresses),
+        gtch => gtch
+            .clone()
+            .atoms_mut()
+            .into_iter()
+            .any(|atom| matches!(atom, Atom::PC | Atom::Relative(_))),
+    }
+}
+
+/// Drop copies and swaps between chunks known to hold the same audio, tracking which chunks share it since the
+/// last barrier
+fn drop_redundant(ir: Vec<Gtch>) -> Vec<Gtch> {
+    let fresh = || std::array::from_fn::<usize, REGISTER_COUNT, _>(|i| i);
+    let mut holds = fresh();
+    let mut next = REGISTER_COUNT;
+    ir.into_iter()
+        .map(|gtch| {
+            let keep = match effect(&gtch) {
+                Effect::Copy(i, j) => {
+                    let redundant = holds[i] == holds[j];
+                    holds[j] = holds[i];
+                    !redundant
+                }
+                Effect::Swap(i, j) => {
+                    holds.swap(i, j);
+                    holds[i] != holds[j]
+                }
+                Effect::Writes(i) => {
+                    holds[i] = next;
+                    next += 1;
+                    true
+                }
+                Effect::Reads => true,
+                Effect::Barrier => {
+                    holds = fresh();
+                    next = REGISTER_COUNT;
+                    true
+                }
+            };
+            if keep {
+                gtch
+            } else {
+                pad(gtch)
+            }
+        })
+        .collect()
+}
+
+/// Drop pairs of swaps which undo each other, including those nested around other such pairs
+fn cancel_swaps(ir: Vec<Gtch>) -> Vec<Gtch> {
+    let mut kept: Vec<Gtch> = Vec::with_capacity(ir.len());
+    for gtch in ir {
+        // Pads from pairs already dropped are passed over
+        let last = kept.iter().rposition(|gtch| !is_pad(gtch));
+        if let (Effect::Swap(i, j), Some(last)) = (effect(&gtch), last) {
+            if let Effect::Swap(k, l) = effect(&kept[last]) {
+                if i != j && ((i, j) == (k, l) || (i, j) == (l, k)) {
+                    kept[last] = pad(kept[last].clone());
+                    kept.push(pad(gtch));
+                    continue;
+                }
+            }
+        }
+        kept.push(gtch);
+    }
+    kept
+}
+
+/// Drop copies and swaps whose results are all overwritten before being read, working back from the end where
+/// everything is read
+fn drop_dead_writes(ir: Vec<Gtch>) -> Vec<Gtch> {
+    let mut live = [true; REGISTER_COUNT];
+    let mut kept = ir
+        .into_iter()
+        .rev()
+        .map(|gtch| {
+            let keep = match effect(&gtch) {
+                Effect::Copy(i, j) => {
+                    let dead = !live[j];
+                    live[j] = false;
+                    live[i] = true;
+                    !dead
+                }
+                Effect::Swap(i, j) => {
+                    let dead = !live[i] && !live[j];
+                    live.swap(i, j);
+                    !dead
+                }
+                Effect::Writes(_) | Effect::Reads | Effect::Barrier => {
+                    live = [true; REGISTER_COUNT];
+                    true
+                }
+            };
+            if keep {
+                gtch
+            } else {
+                pad(gtch)
+            }
+        })
+        .collect::<Vec<_>>();
+    kept.reverse();
+    kept
+}
+
+/// Merge runs of copies like `0>4 1>5 2>6` into a range copy, `0-3>4`. The range assembles to the same ops, but the
+/// other passes can't see into it, so this runs last.
+fn merge_copies(ir: Vec<Gtch>) -> Vec<Gtch> {
+    let mut merged: Vec<Gtch> = Vec::with_capacity(ir.len());
+    for gtch in ir {
+        // Pads are left as single copies, so they still read as pads
+        let padded = is_pad(&gtch) || merged.last().is_some_and(is_pad);
+        if let (Gtch::Copy(Atom::Idx(i), Atom::Idx(j)), Some(Gtch::Copy(from, Atom::Idx(to)))) =
+            (&gtch, merged.last_mut())
+        {
+            let run = match from {
+                Atom::Idx(k) => Some(*k..*k + 1),
+                Atom::Range(r) => Some(r.clone()),
+                _ => None,
+            };
+            // The assembler only takes ranges whose destinations stay within a byte
+            if let Some(run) =
+                run.filter(|run| !padded && run.end == *i && *to + run.len() == *j && *j < 255)
+            {
+                *from = Atom::Range(run.start..i + 1);
+                continue;
+            }
+        }
+        merged.push(gtch);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use dasp::ring_buffer::Fixed;
+    use proptest::prelude::*;
+    use vm::{interpret::Vm, op::Mix, REGISTER_COUNT};
+
+    use super::{is_pad, optimize};
+    use crate::{
+        assemble::assemble,
+        compile::lower,
+        parse::{parse, Atom, Gtch},
+    };
+
+    /// Frames per chunk
+    const CHUNK: usize = 128;
+
+    /// Run the IR through the VM over a buffer with a different ramp in each chunk, returning the whole buffer,
+    /// played frames and all, and the bytes of the bytecode samples were written over
+    fn render(ir: &[Gtch]) -> (Vec<[f32; 1]>, Vec<(usize, u8)>) {
+        let mut buffer = Fixed::from(
+            (0..REGISTER_COUNT * CHUNK)
+                .map(|n| [(n as f32 / 64.0) - 0.5])
+                .collect::<Vec<_>>(),
+        );
+        let assembled = assemble(ir, 256).unwrap();
+        let mut bytecode = assembled.clone();
+        Vm::default().run(&mut bytecode, &mut buffer, false);
+        let written = (0..bytecode.len())
+            .filter(|&n| bytecode[n] != assembled[n])
+            .map(|n| (n, bytecode[n]))
+            .collect();
+        (buffer.iter().copied().collect(), written)
+    }
+
+    fn lowered(program: &str) -> Vec<Gtch> {
+        lower(&parse(program).unwrap()).unwrap()
+    }
+
+    /// The ops left once pads are taken out
+    fn kept(ir: Vec<Gtch>) -> Vec<Gtch> {
+        ir.into_iter().filter(|gtch| !is_pad(gtch)).collect()
+    }
+
+    #[test]
+    fn test_redundant_ops_are_removed() {
+        assert!(kept(optimize(lowered("[4 0>0] 3<>19"))).is_empty());
+        assert!(kept(optimize(lowered("0<>1 2<>3 3<>2 1<>0"))).is_empty());
+        assert_eq!(kept(optimize(lowered("0>1 1>0 0>1 <2"))).len(), 2);
+        // The first copy is overwritten before it's read, and padded out in its place
+        let ir = optimize(lowered("0>1 2>1"));
+        assert_eq!(ir, lowered("1>1 2>1"));
+    }
+
+    #[test]
+    fn test_adjacent_copies_are_merged() {
+        let ir = optimize(lowered("[4 0>4] 6>9 7>10 8>12"));
+        assert!(matches!(
+            &ir[..],
+            [
+                Gtch::Copy(Atom::Range(a), Atom::Idx(4)),
+                Gtch::Copy(Atom::Range(b), Atom::Idx(9)),
+                Gtch::Copy(Atom::Idx(8), Atom::Idx(12)),
+            ] if *a == (0..4) && *b == (6..8)
+        ));
+        // Destinations past a byte can't be a range
+        assert_eq!(optimize(lowered("0>254 1>255")).len(), 2);
+    }
+
+    #[test]
+    fn test_barriers_keep_ops() {
+        // The label could be jumped to with 1 holding anything, and reversing reads the first copy
+        assert_eq!(kept(optimize(lowered("0>1 a: 0>1 <1 2>1"))).len(), 5);
+        // Programs reading the PC are left alone
+        assert_eq!(optimize(lowered("0>0 i>1")), lowered("0>0 i>1"));
+    }
+
+    proptest! {
+        #[test]
+        fn test_optimized_audio_is_identical(ops in prop::collection::vec((0..7usize, 0..20usize, 0..20usize), 0..24)) {
+            // Chunks 1 to 6, some of them wrapped
+            let ir = ops
+                .into_iter()
+                .enumerate()
+                .flat_map(|(n, (op, i, j))| {
+                    let (i, j) = (i % 5 + 1 + 16 * (i / 10), j % 5 + 1 + 16 * (j / 10));
+                    let (a, b) = (Atom::Idx(i), Atom::Idx(j));
+                    match op {
+                        0 => vec![Gtch::Copy(a, b)],
+                        1 => vec![Gtch::Copy(a, b), Gtch::Copy(Atom::Idx(i + 1), Atom::Idx(j + 1))],
+                        2 => vec![Gtch::Swap(a, b)],
+                        3 => vec![Gtch::Reverse(a)],
+                        4 => vec![Gtch::Mix(Mix::Add, a, b)],
+                        5 => vec![Gtch::Sample(a)],
+                        _ => vec![Gtch::Label(format!("l{n}"))],
+                    }
+                })
+                .collect::<Vec<_>>();
+            prop_assert_eq!(render(&optimize(ir.clone())), render(&ir));
+        }
+    }
+}
@@ -4,36 +4,234 @@ use ariadne::{Color, Label, Report, ReportKind, Source};
 use chumsky::{combinator, container::Seq, prelude::*};
 use tracing::instrument;
 use variantly::Variantly;
+use vm::{io::IoSlot, op::Mix};
 
-#[derive(Clone, Debug, Variantly)]
+/// A number which may be given by name or worked out from others, resolved by [compile](crate::compile)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Num(usize),
+    Name(String),
+    /// `i` or `pc`. An expression using it is only known when running.
+    PC,
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Rem,
+}
+
+impl Expr {
+    fn binary(op: BinOp, a: Expr, b: Expr) -> Expr {
+        Expr::Binary(op, Box::new(a), Box::new(b))
+    }
+
+    /// Whether the expression depends on the PC
+    pub fn uses_pc(&self) -> bool {
+        match self {
+            Expr::Num(_) | Expr::Name(_) => false,
+            Expr::PC => true,
+            Expr::Binary(_, a, b) => a.uses_pc() || b.uses_pc(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Variantly)]
 pub enum Atom {
     Idx(usize),
     Range(Range<usize>),
     PC,
+    /// A chunk index offset from [Atom::PC], which moves along as the program runs. Like [Atom::PC] it's parsed
+    /// anywhere, but only Copy's first argument assembles.
+    Relative(isize),
+    /// An index which still has an expression to resolve into an [Atom::Idx] or [Atom::Relative]
+    Expr(Expr),
+    /// A range which still has names to resolve into an [Atom::Range]
+    ExprRange(Expr, Expr),
+    /// The address of a [Gtch::Label], which only a [Gtch::Jump] can take
+    Label(String),
+    /// An index moved by its own step on each pass of a [Gtch::RepeatGroup], rather than the group's stride.
+    /// A step of 0 holds it in place. A bare name starting an op would read as a label, so it takes its step in
+    /// brackets, `(n):1`.
+    Step(Box<Atom>, isize),
 }
 
-#[derive(Clone, Debug, Variantly)]
+#[derive(Clone, Debug, PartialEq, Eq, Variantly)]
 pub enum Gtch {
     Copy(Atom, Atom),
+    /// A number is a chunk index, which continues from the address after it. A label is an exact address, and a
+    /// range is a jump table.
     Jump(Atom),
     Sample(Atom),
     Swap(Atom, Atom),
+    Reverse(Atom),
+    HalfSpeed(Atom, Atom),
+    DoubleSpeed(Atom, Atom),
+    Random(Atom),
+    /// Mix the first chunk into the second
+    Mix(Mix, Atom, Atom),
+    /// Crush a chunk by an amount, the raw byte the VM takes the bit depth from
+    Crush(Atom, usize),
+    /// Decimate a chunk by an amount, the raw byte the VM takes the hold length from
+    Decimate(Atom, usize),
+    /// Copy a byte of the host's I/O page to an address in the bytecode
+    Io(IoSlot, Atom),
+    /// Repeat the children, moving their indices along by `stride` on each pass
     RepeatGroup {
-        max_iters: usize,
+        max_iters: Expr,
+        stride: isize,
+        children: Vec<Gtch>,
+    },
+    /// A named block which is assembled once and run with [Gtch::Call]
+    Sub {
+        name: String,
+        children: Vec<Gtch>,
+    },
+    Call(String),
+    /// Names the address of the next op, to [Gtch::Jump] to
+    Label(String),
+    /// Binds a name for the ops after it in the same block, and any blocks inside them
+    Let {
+        name: String,
+        value: Expr,
+    },
+    /// A macro, scoped like [Gtch::Let] and expanded in place by each [Gtch::Expand]
+    Def {
+        name: String,
+        params: Vec<String>,
         children: Vec<Gtch>,
     },
+    /// Expand a [Gtch::Def], with `span` locating the call in the source for errors
+    Expand {
+        name: String,
+        args: Vec<Expr>,
+        span: Range<usize>,
+    },
+    /// The text after a `#`, kept for [format](crate::format). `span` tells it whether the comment had a line to
+    /// itself.
+    Comment {
+        text: String,
+        span: Range<usize>,
+    },
+}
+
+impl Gtch {
+    /// The chunk and address arguments of the op
+    pub fn atoms_mut(&mut self) -> Vec<&mut Atom> {
+        match self {
+            Gtch::Copy(i, j)
+            | Gtch::Swap(i, j)
+            | Gtch::HalfSpeed(i, j)
+            | Gtch::DoubleSpeed(i, j)
+            | Gtch::Mix(_, i, j) => vec![i, j],
+            Gtch::Jump(i)
+            | Gtch::Sample(i)
+            | Gtch::Reverse(i)
+            | Gtch::Random(i)
+            | Gtch::Crush(i, _)
+            | Gtch::Decimate(i, _)
+            | Gtch::Io(_, i) => vec![i],
+            Gtch::RepeatGroup { .. }
+            | Gtch::Sub { .. }
+            | Gtch::Call(_)
+            | Gtch::Label(_)
+            | Gtch::Let { .. }
+            | Gtch::Def { .. }
+            | Gtch::Expand { .. }
+            | Gtch::Comment { .. } => vec![],
+        }
+    }
 }
 
 fn parser<'a>() -> impl Parser<'a, &'a str, Vec<Gtch>, extra::Err<Rich<'a, char>>> {
-    recursive(|tree| {
-        let range = text::int(10)
-            .then_ignore(just("-"))
-            .then(text::int(10))
-            .map(|(x, y): (&str, &str)| Atom::Range(x.parse().unwrap()..y.parse().unwrap()));
-        let idx = text::int(10).map(|i: &str| Atom::Idx(i.parse().unwrap()));
-        let pc = just("i").to(Atom::PC);
+    let comment = just("#")
+        .ignore_then(none_of("\r\n").repeated().to_slice())
+        .map_with(|text: &str, e| {
+            let span: SimpleSpan = e.span();
+            Gtch::Comment {
+                text: text.trim_end().to_string(),
+                span: span.into_range(),
+            }
+        });
+
+    // Names can't contain `_`, which would run into `_>`. `i` and `pc` are the PC.
+    let name = any()
+        .filter(char::is_ascii_alphabetic)
+        .then(any().filter(char::is_ascii_alphanumeric).repeated())
+        .to_slice()
+        .try_map(|name: &str, span| match name {
+            "i" | "pc" => Err(Rich::custom(
+                span,
+                format!("`{name}` cannot be used as a name"),
+            )),
+            name => Ok(name.to_string()),
+        });
+    let num = text::int(10).try_map(|n: &str, span| {
+        n.parse::<usize>()
+            .map_err(|_| Rich::custom(span, format!("`{n}` is too large a number")))
+    });
+    let sign = choice((just("+").to(1), just("-").to(-1)));
+    let signed = sign
+        .or_not()
+        .then(num)
+        .map(|(sign, n): (Option<isize>, usize)| sign.unwrap_or(1) * n as isize);
+    // A stride needs its sign, or it would read as the start of an op
+    let stride = sign
+        .then(num)
+        .map(|(sign, n): (isize, usize)| sign * n as isize);
 
-        let atom = choice((range, idx, pc));
+    let mut expr = Recursive::declare();
+    let term = choice((
+        num.map(Expr::Num),
+        name.map(Expr::Name),
+        choice((just("i"), just("pc"))).to(Expr::PC),
+        expr.clone().padded().delimited_by(just("("), just(")")),
+    ));
+    let product = term.clone().foldl(
+        choice((just("*").to(BinOp::Mul), just("%").to(BinOp::Rem)))
+            .then(term)
+            .repeated(),
+        |a, (op, b)| Expr::binary(op, a, b),
+    );
+    let sum = |ops: Boxed<'a, 'a, &'a str, BinOp, extra::Err<Rich<'a, char>>>| {
+        product
+            .clone()
+            .foldl(ops.then(product.clone()).repeated(), |a, (op, b)| {
+                Expr::binary(op, a, b)
+            })
+    };
+    expr.define(sum(choice((
+        just("+").to(BinOp::Add),
+        just("-").to(BinOp::Sub),
+    ))
+    .boxed()));
+    let expr = expr.boxed();
+    // Outside of brackets `-` makes a range
+    let value = sum(just("+").to(BinOp::Add).boxed()).boxed();
+
+    recursive(|tree| {
+        // Plain numbers need no resolving, so they go straight to an index or range. A range can't move with
+        // the PC, so `i-1` subtracts.
+        let atom = value
+            .clone()
+            .then(just("-").ignore_then(value.clone()).or_not())
+            .map(|(x, y)| match (x, y) {
+                (Expr::Num(x), None) => Atom::Idx(x),
+                (Expr::PC, None) => Atom::PC,
+                (x, None) => Atom::Expr(x),
+                (x, Some(y)) if x.uses_pc() => Atom::Expr(Expr::binary(BinOp::Sub, x, y)),
+                (Expr::Num(x), Some(Expr::Num(y))) => Atom::Range(x..y),
+                (x, Some(y)) => Atom::ExprRange(x, y),
+            })
+            .then(just(":").ignore_then(signed).or_not())
+            .map(|(atom, step)| match step {
+                Some(step) => Atom::Step(Box::new(atom), step),
+                None => atom,
+            })
+            .boxed();
 
         let copy = atom
             .clone()
@@ -41,37 +239,167 @@ fn parser<'a>() -> impl Parser<'a, &'a str, Vec<Gtch>, extra::Err<Rich<'a, char>
             .then(atom.clone())
             .map(|(a1, a2)| Gtch::Copy(a1, a2));
 
-        let jump = just(".").ignore_then(atom.clone()).map(Gtch::Jump);
+        // Labels are tried before any op, so `loop:0>1` is a label and a copy rather than a step
+        let label = name.then_ignore(just(":")).map(Gtch::Label);
+        let jump = just(".")
+            .ignore_then(choice((name.map(Atom::Label), atom.clone())))
+            .map(Gtch::Jump);
 
         let sample = just("~").ignore_then(atom.clone()).map(Gtch::Sample);
 
         let swap = atom
             .clone()
             .then_ignore(just("<>"))
-            .then(atom)
+            .then(atom.clone())
             .map(|(a1, a2)| Gtch::Swap(a1, a2));
 
-        let parse_loop = text::int(10)
-            .map(|d: &str| d.parse().unwrap())
+        let reverse = just("<").ignore_then(atom.clone()).map(Gtch::Reverse);
+
+        let random = just("?").ignore_then(atom.clone()).map(Gtch::Random);
+
+        let half_speed = atom
+            .clone()
+            .then_ignore(just("_>"))
+            .then(atom.clone())
+            .map(|(a1, a2)| Gtch::HalfSpeed(a1, a2));
+
+        let double_speed = atom
+            .clone()
+            .then_ignore(just(">>"))
+            .then(atom.clone())
+            .map(|(a1, a2)| Gtch::DoubleSpeed(a1, a2));
+
+        let mix_op = choice((
+            just("+>").to(Mix::Add),
+            just("*>").to(Mix::Multiply),
+            just("&>").to(Mix::Average),
+            just("^>").to(Mix::Max),
+        ));
+        let mix = atom
+            .clone()
+            .then(mix_op)
+            .then(atom.clone())
+            .map(|((a1, mix), a2)| Gtch::Mix(mix, a1, a2));
+
+        let io_slot = text::ident().try_map(|name: &str, span| {
+            IoSlot::ALL
+                .into_iter()
+                .find(|slot| slot.name() == name)
+                .ok_or_else(|| Rich::custom(span, format!("Unknown I/O slot `{name}`")))
+        });
+        let io = just("$")
+            .ignore_then(io_slot)
+            .then_ignore(just(">"))
+            .then(atom.clone())
+            .map(|(slot, a)| Gtch::Io(slot, a));
+
+        let amount = num;
+        let crush = atom
+            .clone()
+            .then_ignore(just("!"))
+            .then(amount)
+            .map(|(a, n)| Gtch::Crush(a, n));
+        let decimate = atom
+            .then_ignore(just("/"))
+            .then(amount)
+            .map(|(a, n)| Gtch::Decimate(a, n));
+
+        let parse_loop = expr
+            .clone()
             .padded()
-            .then(tree.or_not().padded())
+            .then(stride.padded().or_not())
+            .then(tree.clone().or_not().padded())
             .delimited_by(just("["), just("]"))
-            .map(|(iterations, children)| Gtch::RepeatGroup {
+            .map(|((iterations, stride), children)| Gtch::RepeatGroup {
                 max_iters: iterations,
+                stride: stride.unwrap_or(1),
                 children: children.unwrap_or(vec![]),
             });
 
-        choice((copy, jump, sample, swap, parse_loop))
-            .padded()
-            .repeated()
-            .collect()
+        let sub = text::keyword("sub")
+            .ignore_then(text::ident().padded())
+            .then(
+                tree.clone()
+                    .or_not()
+                    .padded()
+                    .delimited_by(just("{"), just("}")),
+            )
+            .map(|(name, children): (&str, _)| Gtch::Sub {
+                name: name.to_string(),
+                children: children.unwrap_or(vec![]),
+            });
+
+        let binding = text::keyword("let")
+            .ignore_then(name.padded())
+            .then_ignore(just("="))
+            .then(expr.clone().padded())
+            .map(|(name, value)| Gtch::Let { name, value });
+
+        let def = text::keyword("def")
+            .ignore_then(text::ident().padded())
+            .then(
+                name.padded()
+                    .separated_by(just(","))
+                    .collect()
+                    .delimited_by(just("("), just(")"))
+                    .padded(),
+            )
+            .then(tree.or_not().padded().delimited_by(just("{"), just("}")))
+            .map(|((name, params), children): ((&str, _), _)| Gtch::Def {
+                name: name.to_string(),
+                params,
+                children: children.unwrap_or(vec![]),
+            });
+
+        let expand = text::ident()
+            .then(
+                expr.clone()
+                    .padded()
+                    .separated_by(just(","))
+                    .collect()
+                    .delimited_by(just("("), just(")")),
+            )
+            .map_with(|(name, args): (&str, _), e| Gtch::Expand {
+                name: name.to_string(),
+                args,
+                span: e.span().into_range(),
+            });
+
+        let call = just("@")
+            .ignore_then(text::ident())
+            .map(|name: &str| Gtch::Call(name.to_string()));
+
+        choice((
+            label,
+            binding,
+            double_speed,
+            half_speed,
+            copy,
+            jump,
+            sample,
+            swap,
+            reverse,
+            random,
+            mix,
+            crush,
+            decimate,
+            io,
+            parse_loop,
+            sub,
+            def,
+            call,
+            expand,
+            comment,
+        ))
+        .padded()
+        .repeated()
+        .collect()
     })
 }
 
 #[instrument(skip(s))]
 pub fn parse(s: &str) -> Result<Vec<Gtch>, Vec<Rich<char>>> {
     let (gtch, errs) = parser().parse(s.trim()).into_output_errors();
-    println!("{:#?}", gtch);
     errs.iter().for_each(|e| {
         let _ = Report::build(ReportKind::Error, e.span().into_range())
             .with_message(e.to_string())
@@ -88,7 +416,7 @@ pub fn parse(s: &str) -> Result<Vec<Gtch>, Vec<Rich<char>>> {
 
 #[cfg(test)]
 mod tests {
-    use crate::parse::{Atom, Gtch};
+    use crate::parse::{Atom, BinOp, Expr, Gtch};
 
     use super::parse;
     use proptest::prelude::*;
@@ -97,6 +425,143 @@ mod tests {
     fn test_parsing() {
         parse("1>25").unwrap();
         parse(".2 50>25").unwrap();
+        parse("<3 0_>1 2>>3").unwrap();
+        parse("?4 ?i").unwrap();
+        parse("0+>3 0*>3 1&>2 i^>4").unwrap();
+        parse("3!4 2/16").unwrap();
+        parse("$tempo>40 $macro2>i").unwrap();
+        assert!(parse("$volume>40").is_err());
+    }
+
+    #[test]
+    fn test_parsing_subroutines() {
+        let gtch = parse("sub stutter { 0>1 1>2 } @stutter").unwrap();
+        assert!(
+            matches!(&gtch[0], Gtch::Sub { name, children } if name == "stutter" && children.len() == 2)
+        );
+        assert!(matches!(&gtch[1], Gtch::Call(name) if name == "stutter"));
+    }
+
+    #[test]
+    fn test_parsing_comments_and_labels() {
+        let gtch = parse(
+            "# a stutter
+            loop: 0>1 # copy
+            [2 # twice
+              1>2
+            ]
+            .loop",
+        )
+        .unwrap();
+        assert!(
+            matches!(&gtch[0], Gtch::Comment { text, span } if text == " a stutter" && *span == (0..11))
+        );
+        assert!(matches!(&gtch[1], Gtch::Label(name) if name == "loop"));
+        assert!(matches!(&gtch[3], Gtch::Comment { text, .. } if text == " copy"));
+        assert!(matches!(&gtch[4], Gtch::RepeatGroup { children, .. } if children.len() == 2));
+        assert!(matches!(&gtch[5], Gtch::Jump(Atom::Label(name)) if name == "loop"));
+        assert!(matches!(parse(".i").unwrap()[0], Gtch::Jump(Atom::PC)));
+        assert!(matches!(
+            &parse("# nothing but comments").unwrap()[..],
+            [Gtch::Comment { .. }]
+        ));
+        assert!(parse("i: .i").is_err());
+        // No separator is needed after a label
+        assert!(matches!(
+            &parse("loop:0>1 (n):0>1").unwrap()[..],
+            [
+                Gtch::Label(name),
+                Gtch::Copy(Atom::Idx(0), Atom::Idx(1)),
+                Gtch::Copy(Atom::Step(n, 0), Atom::Idx(1)),
+            ] if name == "loop" && matches!(**n, Atom::Expr(Expr::Name(_)))
+        ));
+        // Label names follow the same rule as other names, as `_` would run into `_>`
+        assert!(parse("a_b: .a_b").is_err());
+        assert!(parse("2a: .2a").is_err());
+    }
+
+    #[test]
+    fn test_parsing_bindings() {
+        let gtch =
+            parse("let kick = 3 let snare = kick [kick kick>snare] 0-kick>4 i_>kick").unwrap();
+        assert!(matches!(&gtch[0], Gtch::Let { name, value: Expr::Num(3) } if name == "kick"));
+        assert!(matches!(&gtch[1], Gtch::Let { value: Expr::Name(value), .. } if value == "kick"));
+        assert!(matches!(
+            &gtch[2],
+            Gtch::RepeatGroup {
+                max_iters: Expr::Name(_),
+                ..
+            }
+        ));
+        assert!(matches!(
+            &gtch[3],
+            Gtch::Copy(Atom::ExprRange(Expr::Num(0), _), Atom::Idx(4))
+        ));
+        assert!(matches!(&gtch[4], Gtch::HalfSpeed(Atom::PC, Atom::Expr(_))));
+        assert!(parse("let i = 3").is_err());
+    }
+
+    #[test]
+    fn test_parsing_expressions() {
+        let gtch = parse("i+2>k*2 (a+1)%16>0 pc-1>3 a-b>0 (a-b)>0 k*>2").unwrap();
+        assert!(
+            matches!(&gtch[0], Gtch::Copy(Atom::Expr(i), Atom::Expr(Expr::Binary(BinOp::Mul, _, _))) if i.uses_pc())
+        );
+        assert!(matches!(
+            &gtch[1],
+            Gtch::Copy(Atom::Expr(Expr::Binary(BinOp::Rem, _, _)), _)
+        ));
+        assert!(matches!(
+            &gtch[2],
+            Gtch::Copy(Atom::Expr(Expr::Binary(BinOp::Sub, _, _)), _)
+        ));
+        assert!(matches!(&gtch[3], Gtch::Copy(Atom::ExprRange(_, _), _)));
+        assert!(matches!(
+            &gtch[4],
+            Gtch::Copy(Atom::Expr(Expr::Binary(BinOp::Sub, _, _)), _)
+        ));
+        assert!(matches!(
+            &gtch[5],
+            Gtch::Mix(_, Atom::Expr(Expr::Name(_)), Atom::Idx(2))
+        ));
+        // Too large for a usize, anywhere a number goes
+        for program in [
+            "99999999999999999999999>0",
+            "let n = 99999999999999999999999",
+            "0:99999999999999999999999>1",
+            "[99999999999999999999999 0>1]",
+            "0!99999999999999999999999",
+        ] {
+            assert!(parse(program).is_err(), "{program}");
+        }
+    }
+
+    #[test]
+    fn test_parsing_macros() {
+        let gtch = parse("def stutter(a, n) { [n a>a+1] } stutter(3, 2*2) stutter(0,1)").unwrap();
+        assert!(
+            matches!(&gtch[0], Gtch::Def { name, params, children } if name == "stutter" && params.len() == 2 && children.len() == 1)
+        );
+        assert!(
+            matches!(&gtch[1], Gtch::Expand { name, args, span } if name == "stutter" && args.len() == 2 && *span == (32..47))
+        );
+        assert!(matches!(&gtch[2], Gtch::Expand { .. }));
+    }
+
+    #[test]
+    fn test_parsing_strides() {
+        let gtch = parse("[4 -2 0>7:-1] [3 0:0+>i:+2] [2 0>1]").unwrap();
+        assert!(matches!(
+            &gtch[0],
+            Gtch::RepeatGroup { stride: -2, children, .. }
+                if matches!(&children[0], Gtch::Copy(Atom::Idx(0), Atom::Step(j, -1)) if matches!(**j, Atom::Idx(7)))
+        ));
+        assert!(matches!(
+            &gtch[1],
+            Gtch::RepeatGroup { stride: 1, children, .. }
+                if matches!(&children[0], Gtch::Mix(_, Atom::Step(_, 0), Atom::Step(_, 2)))
+        ));
+        assert!(matches!(&gtch[2], Gtch::RepeatGroup { stride: 1, .. }));
     }
 
     #[test]
@@ -107,7 +572,7 @@ mod tests {
     proptest! {
         #[test]
         fn test_parsing_loop(ops in prop::collection::vec(prop::sample::select(&[
-            "~0", "0>1", "0<>1", ".0"
+            "~0", "0>1", "0<>1", ".0", "<0", "0_>1", "0>>1", "?0", "0+>1", "0*>1", "0&>1", "0^>1", "0!4", "0/4", "$beat>0", "i+1>0", "~0-2", ".4-8", "0-2<>4"
         ]), 0..10).prop_map(|ops| ops.join(" "))) {
             let program = ["[0", &ops, "]"].join(" ");
             let result = parse(&program);
@@ -0,0 +1,150 @@
+/// A general purpose register, `0..GP_REGISTER_COUNT`. Out of range indices wrap.
+pub type Reg = u8;
+
+pub const GP_REGISTER_COUNT: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Opcode {
+    /// Do nothing. Zeroed memory is all `Nop`s.
+    Nop,
+    /// Stop the run
+    Halt,
+    /// Load a 16 bit immediate into `r`
+    Li,
+    /// Load the byte at the address in `a` into `r`
+    Ld,
+    /// Store the low byte of `r` at the address in `a`
+    St,
+    /// `r = r + a`, wrapping
+    Add,
+    /// `r = r - a`, wrapping
+    Sub,
+    /// `r = r * a`, wrapping
+    Mul,
+    /// `r = r & a`
+    And,
+    /// `r = r ^ a`
+    Xor,
+    /// Add a signed 8 bit immediate to `r`, wrapping
+    Addi,
+    /// Continue from a 16 bit immediate address
+    Jmp,
+    /// Continue from a 16 bit immediate address if `r` is not zero
+    Jnz,
+    /// Copy a read-only [Special] register into `r`
+    Rdr,
+}
+
+/// The read-only registers, as numbered for [Opcode::Rdr]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Special {
+    DataStart,
+    MemoryLen,
+    Pc,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Nop,
+    Halt,
+    Li(Reg, u16),
+    Ld(Reg, Reg),
+    St(Reg, Reg),
+    Add(Reg, Reg),
+    Sub(Reg, Reg),
+    Mul(Reg, Reg),
+    And(Reg, Reg),
+    Xor(Reg, Reg),
+    Addi(Reg, i8),
+    Jmp(u16),
+    Jnz(Reg, u16),
+    Rdr(Reg, Special),
+}
+
+impl Opcode {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        use Opcode::*;
+        [
+            Nop, Halt, Li, Ld, St, Add, Sub, Mul, And, Xor, Addi, Jmp, Jnz, Rdr,
+        ]
+        .get(byte as usize)
+        .copied()
+    }
+}
+
+impl Instruction {
+    /// Decode the instruction at `pc`, and its length in bytes.
+    ///
+    /// Returns [None] if the instruction runs off the end of memory. Bytes which aren't opcodes decode as [Instruction::Nop].
+    pub fn decode(memory: &[u8], pc: usize) -> Option<(Self, usize)> {
+        let byte = |offset: usize| memory.get(pc + offset).copied();
+        let imm16 = |offset: usize| Some(u16::from_le_bytes([byte(offset)?, byte(offset + 1)?]));
+
+        let Some(opcode) = Opcode::from_byte(byte(0)?) else {
+            return Some((Instruction::Nop, 1));
+        };
+        let instruction = match opcode {
+            Opcode::Nop => Instruction::Nop,
+            Opcode::Halt => Instruction::Halt,
+            Opcode::Li => Instruction::Li(byte(1)?, imm16(2)?),
+            Opcode::Ld => Instruction::Ld(byte(1)?, byte(2)?),
+            Opcode::St => Instruction::St(byte(1)?, byte(2)?),
+            Opcode::Add => Instruction::Add(byte(1)?, byte(2)?),
+            Opcode::Sub => Instruction::Sub(byte(1)?, byte(2)?),
+            Opcode::Mul => Instruction::Mul(byte(1)?, byte(2)?),
+            Opcode::And => Instruction::And(byte(1)?, byte(2)?),
+            Opcode::Xor => Instruction::Xor(byte(1)?, byte(2)?),
+            Opcode::Addi => Instruction::Addi(byte(1)?, byte(2)? as i8),
+            Opcode::Jmp => Instruction::Jmp(imm16(1)?),
+            Opcode::Jnz => Instruction::Jnz(byte(1)?, imm16(2)?),
+            Opcode::Rdr => {
+                let special = match byte(2)? % 3 {
+                    0 => Special::DataStart,
+                    1 => Special::MemoryLen,
+                    _ => Special::Pc,
+                };
+                Instruction::Rdr(byte(1)?, special)
+            }
+        };
+        Some((instruction, instruction.size()))
+    }
+
+    /// Append the instruction's bytes to `out`
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Instruction::Nop => out.push(Opcode::Nop as u8),
+            Instruction::Halt => out.push(Opcode::Halt as u8),
+            Instruction::Li(r, imm) => {
+                out.extend([Opcode::Li as u8, r]);
+                out.extend(imm.to_le_bytes());
+            }
+            Instruction::Ld(r, a) => out.extend([Opcode::Ld as u8, r, a]),
+            Instruction::St(r, a) => out.extend([Opcode::St as u8, r, a]),
+            Instruction::Add(r, a) => out.extend([Opcode::Add as u8, r, a]),
+            Instruction::Sub(r, a) => out.extend([Opcode::Sub as u8, r, a]),
+            Instruction::Mul(r, a) => out.extend([Opcode::Mul as u8, r, a]),
+            Instruction::And(r, a) => out.extend([Opcode::And as u8, r, a]),
+            Instruction::Xor(r, a) => out.extend([Opcode::Xor as u8, r, a]),
+            Instruction::Addi(r, imm) => out.extend([Opcode::Addi as u8, r, imm as u8]),
+            Instruction::Jmp(addr) => {
+                out.push(Opcode::Jmp as u8);
+                out.extend(addr.to_le_bytes());
+            }
+            Instruction::Jnz(r, addr) => {
+                out.extend([Opcode::Jnz as u8, r]);
+                out.extend(addr.to_le_bytes());
+            }
+            Instruction::Rdr(r, special) => out.extend([Opcode::Rdr as u8, r, special as u8]),
+        }
+    }
+
+    /// The length of the instruction in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::Nop | Instruction::Halt => 1,
+            Instruction::Jmp(_) => 3,
+            Instruction::Li(_, _) | Instruction::Jnz(_, _) => 4,
+            _ => 3,
+        }
+    }
+}
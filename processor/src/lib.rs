@@ -1,4 +1,7 @@
+pub mod instruction;
+
 use bitfield_struct::bitfield;
+use instruction::{Instruction, Reg, Special, GP_REGISTER_COUNT};
 
 #[bitfield(u32)]
 pub struct Offsets {
@@ -13,33 +16,337 @@ pub struct Registers {
     offsets: Offsets,
     memory_len: u32,
     pc: u32,
-    // r1: u32,
-    // r2: u32,
-    // r3: u32,
-    // r4: u32
+    // General purpose registers
+    r1: u32,
+    r2: u32,
+    r3: u32,
+    r4: u32,
 }
 
 impl Registers {
+    fn new(data_start: u16) -> Self {
+        Self {
+            offsets: Offsets::new().with_data_start(data_start),
+            memory_len: 0,
+            pc: 0,
+            r1: 0,
+            r2: 0,
+            r3: 0,
+            r4: 0,
+        }
+    }
+
     pub fn reset(&mut self) {
         self.pc = 0;
     }
+
+    /// General purpose register `r`, wrapping out of range indices
+    pub fn gp(&self, r: Reg) -> u32 {
+        match r % GP_REGISTER_COUNT {
+            0 => self.r1,
+            1 => self.r2,
+            2 => self.r3,
+            _ => self.r4,
+        }
+    }
+
+    fn gp_mut(&mut self, r: Reg) -> &mut u32 {
+        match r % GP_REGISTER_COUNT {
+            0 => &mut self.r1,
+            1 => &mut self.r2,
+            2 => &mut self.r3,
+            _ => &mut self.r4,
+        }
+    }
+
+    fn special(&self, special: Special) -> u32 {
+        match special {
+            Special::DataStart => self.offsets.data_start() as u32,
+            Special::MemoryLen => self.memory_len,
+            Special::Pc => self.pc,
+        }
+    }
 }
 
+/// A processor with unified memory: code lives in `memory[..data_start]` and audio in
+/// `memory[data_start..]`, but nothing stops either from reading, writing or running the other.
+///
+/// Addresses wrap around the end of memory. General purpose registers persist between runs.
 pub struct VmProcessor {
     registers: Registers,
+    /// The maximum number of instructions per run, so the audio thread never hangs
+    max_steps: usize,
+}
+
+impl VmProcessor {
+    pub fn new(data_start: u16, max_steps: usize) -> Self {
+        Self {
+            registers: Registers::new(data_start),
+            max_steps,
+        }
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn data_start(&self) -> usize {
+        self.registers.offsets.data_start() as usize
+    }
+
+    pub fn set_max_steps(&mut self, max_steps: usize) {
+        self.max_steps = max_steps;
+    }
+
+    /// Run a single instruction. Returns false once the run should stop.
+    fn step(&mut self, memory: &mut [u8]) -> bool {
+        let pc = self.registers.pc as usize;
+        let Some((instruction, size)) = Instruction::decode(memory, pc) else {
+            return false;
+        };
+        self.registers.pc = (pc + size) as u32;
+
+        let address = |value: u32| value as usize % memory.len();
+        let regs = &mut self.registers;
+        match instruction {
+            Instruction::Nop => {}
+            Instruction::Halt => return false,
+            Instruction::Li(r, imm) => *regs.gp_mut(r) = imm as u32,
+            Instruction::Ld(r, a) => *regs.gp_mut(r) = memory[address(regs.gp(a))] as u32,
+            Instruction::St(r, a) => memory[address(regs.gp(a))] = regs.gp(r) as u8,
+            Instruction::Add(r, a) => *regs.gp_mut(r) = regs.gp(r).wrapping_add(regs.gp(a)),
+            Instruction::Sub(r, a) => *regs.gp_mut(r) = regs.gp(r).wrapping_sub(regs.gp(a)),
+            Instruction::Mul(r, a) => *regs.gp_mut(r) = regs.gp(r).wrapping_mul(regs.gp(a)),
+            Instruction::And(r, a) => *regs.gp_mut(r) = regs.gp(r) & regs.gp(a),
+            Instruction::Xor(r, a) => *regs.gp_mut(r) = regs.gp(r) ^ regs.gp(a),
+            Instruction::Addi(r, imm) => {
+                *regs.gp_mut(r) = regs.gp(r).wrapping_add_signed(imm as i32)
+            }
+            Instruction::Jmp(addr) => regs.pc = address(addr as u32) as u32,
+            Instruction::Jnz(r, addr) => {
+                if regs.gp(r) != 0 {
+                    regs.pc = address(addr as u32) as u32;
+                }
+            }
+            Instruction::Rdr(r, special) => *regs.gp_mut(r) = regs.special(special),
+        }
+        true
+    }
 }
 
 impl Processor for VmProcessor {
     fn reset(&mut self) {
-        todo!()
+        self.registers = Registers::new(self.registers.offsets.data_start());
     }
 
-    fn run(&mut self, memory: & mut [u8]) {
-        todo!()
+    fn run(&mut self, memory: &mut [u8]) {
+        self.registers.reset();
+        self.registers.memory_len = memory.len() as u32;
+        if memory.is_empty() {
+            return;
+        }
+        for _ in 0..self.max_steps {
+            if !self.step(memory) {
+                break;
+            }
+        }
     }
 }
 
 pub trait Processor {
     fn reset(&mut self);
-    fn run(&mut self, memory: & mut [u8]);
+    fn run(&mut self, memory: &mut [u8]);
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{Processor, VmProcessor};
+    use crate::instruction::{Instruction, Special};
+
+    const DATA_START: u16 = 64;
+
+    /// Assemble `program` into the code region of otherwise zeroed memory
+    fn memory_with(program: &[Instruction], data: &[u8]) -> Vec<u8> {
+        let mut memory = vec![];
+        for instruction in program {
+            instruction.encode(&mut memory);
+        }
+        assert!(memory.len() <= DATA_START as usize);
+        memory.resize(DATA_START as usize, 0);
+        memory.extend(data);
+        memory
+    }
+
+    fn run(program: &[Instruction], data: &[u8]) -> (VmProcessor, Vec<u8>) {
+        let mut memory = memory_with(program, data);
+        let mut processor = VmProcessor::new(DATA_START, 1024);
+        processor.run(&mut memory);
+        (processor, memory)
+    }
+
+    #[test]
+    fn test_load_and_store() {
+        let (_, memory) = run(
+            &[
+                Instruction::Li(0, DATA_START),
+                Instruction::Li(1, DATA_START + 1),
+                Instruction::Ld(2, 0),
+                Instruction::St(2, 1),
+                Instruction::Halt,
+            ],
+            &[42, 0],
+        );
+        assert_eq!(&memory[DATA_START as usize..], &[42, 42]);
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let (processor, _) = run(
+            &[
+                Instruction::Li(0, 6),
+                Instruction::Li(1, 7),
+                Instruction::Mul(0, 1),
+                Instruction::Addi(0, -2),
+                Instruction::Li(2, 0xff),
+                Instruction::And(2, 0),
+                Instruction::Li(3, 1),
+                Instruction::Sub(3, 2),
+                Instruction::Halt,
+            ],
+            &[],
+        );
+        let regs = processor.registers();
+        assert_eq!(regs.gp(0), 40);
+        assert_eq!(regs.gp(2), 40);
+        assert_eq!(regs.gp(3), 1u32.wrapping_sub(40));
+    }
+
+    #[test]
+    fn test_loop() {
+        // Count r0 down from 5, adding 3 to r1 each time
+        let program = [
+            Instruction::Li(0, 5),
+            Instruction::Li(2, 3),
+            // 8:
+            Instruction::Add(1, 2),
+            Instruction::Addi(0, -1),
+            Instruction::Jnz(0, 8),
+            Instruction::Halt,
+        ];
+        let (processor, _) = run(&program, &[]);
+        assert_eq!(processor.registers().gp(0), 0);
+        assert_eq!(processor.registers().gp(1), 15);
+    }
+
+    #[test]
+    fn test_read_only_registers() {
+        let (processor, _) = run(
+            &[
+                Instruction::Rdr(0, Special::DataStart),
+                Instruction::Rdr(1, Special::MemoryLen),
+                Instruction::Rdr(2, Special::Pc),
+                Instruction::Halt,
+            ],
+            &[0; 16],
+        );
+        let regs = processor.registers();
+        assert_eq!(regs.gp(0), DATA_START as u32);
+        assert_eq!(regs.gp(1), DATA_START as u32 + 16);
+        // The PC has already moved past the instruction reading it
+        assert_eq!(regs.gp(2), 9);
+    }
+
+    #[test]
+    fn test_data_runs_as_code() {
+        // Jump into the data region, where the "audio" happens to be a Halt followed by garbage
+        let (processor, _) = run(
+            &[Instruction::Li(0, 1), Instruction::Jmp(DATA_START)],
+            &[
+                crate::instruction::Opcode::Li as u8,
+                0,
+                9,
+                0,
+                crate::instruction::Opcode::Halt as u8,
+            ],
+        );
+        assert_eq!(processor.registers().gp(0), 9);
+    }
+
+    #[test]
+    fn test_code_overwrites_itself() {
+        // Store a Halt over the Jmp that would otherwise loop forever
+        let halt = crate::instruction::Opcode::Halt as u16;
+        let program = [
+            Instruction::Li(0, halt),
+            Instruction::Li(1, 14),
+            // 8:
+            Instruction::Addi(2, 1),
+            Instruction::St(0, 1),
+            // 14:
+            Instruction::Jmp(8),
+        ];
+        let (processor, memory) = run(&program, &[]);
+        assert_eq!(processor.registers().gp(2), 1);
+        assert_eq!(memory[14], halt as u8);
+    }
+
+    #[test]
+    fn test_addresses_wrap() {
+        let (_, memory) = run(
+            &[
+                Instruction::Li(0, 7),
+                Instruction::Li(1, DATA_START + 2),
+                Instruction::St(0, 1),
+                Instruction::Halt,
+            ],
+            &[0, 0],
+        );
+        assert_eq!(memory[0], 7);
+    }
+
+    #[test]
+    fn test_jumps_wrap() {
+        // Jumping one past the end of memory lands back on the Li at 0, so r1 counts up until it's halted
+        let memory_len = DATA_START as u32 + 2;
+        let program = [
+            Instruction::Addi(1, 1),
+            Instruction::Li(0, 3),
+            Instruction::Sub(0, 1),
+            Instruction::Jnz(0, memory_len as u16),
+            Instruction::Halt,
+        ];
+        let (processor, _) = run(&program, &[0, 0]);
+        assert_eq!(processor.registers().gp(1), 3);
+    }
+
+    #[test]
+    fn test_encoding_round_trips() {
+        let program = [
+            Instruction::Li(3, 0xbeef),
+            Instruction::Addi(1, -128),
+            Instruction::Jnz(2, 300),
+            Instruction::Rdr(0, Special::Pc),
+        ];
+        let memory = memory_with(&program, &[]);
+        let mut pc = 0;
+        for instruction in program {
+            let (decoded, size) = Instruction::decode(&memory, pc).unwrap();
+            assert_eq!(decoded, instruction);
+            pc += size;
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_arbitrary_memory_always_terminates(
+            mut memory in prop::collection::vec(any::<u8>(), 0..1024),
+            max_steps in 0..4096usize,
+        ) {
+            let mut processor = VmProcessor::new(DATA_START, max_steps);
+            processor.run(&mut memory);
+            processor.run(&mut memory);
+        }
+    }
 }
@@ -1,15 +1,22 @@
-use dasp::ring_buffer::Fixed;
+use dasp::{ring_buffer::Fixed, Sample};
 use nih_plug::buffer::Buffer;
+use vm::{backend::ChannelView, cores::Cores};
+
+/// Samples are 16 bit in the processor engine's memory
+const BYTES_PER_SAMPLE: usize = 2;
+
+/// The most audio the processor engine can address after its code, as its addresses are 16 bit
+const PROCESSOR_DATA_LIMIT: usize = (1 << 16) - crate::PROCESSOR_CODE_LEN;
 
 #[derive(Debug)]
-pub struct DelayBuffer {
-    pub buffer: Fixed<Vec<[f32; 2]>>,
+pub struct DelayBuffer<const C: usize> {
+    pub buffer: Fixed<Vec<[f32; C]>>,
 }
 
-impl DelayBuffer {
+impl<const C: usize> DelayBuffer<C> {
     pub fn new(len: usize) -> Self {
         Self {
-            buffer: Fixed::from(vec![[0.0, 0.0]; len]),
+            buffer: Fixed::from(vec![[0.0; C]; len]),
         }
     }
 
@@ -17,9 +24,12 @@ impl DelayBuffer {
     pub fn ingest_audio(&mut self, audio: &mut Buffer) {
         #[cfg(feature = "tracing")]
         let _span = tracy_client::span!("delay buffer: Ingest new audio samples");
-        let audio = audio.as_slice();
-        for (left, right) in audio[0].iter().zip(audio[1].iter()) {
-            self.buffer.push([*left, *right]);
+        for mut chan_iter in audio.iter_samples() {
+            let mut frame = [0.0; C];
+            for (channel, sample) in frame.iter_mut().zip(chan_iter.iter_mut()) {
+                *channel = *sample;
+            }
+            self.buffer.push(frame);
         }
     }
 
@@ -28,8 +38,158 @@ impl DelayBuffer {
         #[cfg(feature = "tracing")]
         let _span = tracy_client::span!("delay buffer: Fill audio buffer with output");
         for (frame, mut chan_iter) in self.buffer.iter().zip(audio.iter_samples()) {
-            *chan_iter.get_mut(0).unwrap() = frame[0];
-            *chan_iter.get_mut(1).unwrap() = frame[1];
+            for (sample, channel) in chan_iter.iter_mut().zip(frame) {
+                *sample = *channel;
+            }
+        }
+    }
+
+    /// Run the VM cores over the buffer. Linked runs move every channel together, otherwise the cores run
+    /// over each channel in turn and they drift apart.
+    pub fn run_vm(
+        &mut self,
+        cores: &mut Cores,
+        bytecode: &mut [u8],
+        self_modify: bool,
+        linked: bool,
+    ) {
+        if linked || C == 1 {
+            cores.run(bytecode, &mut self.buffer, self_modify);
+            return;
+        }
+        for channel in 0..C {
+            let mut view = ChannelView {
+                buffer: &mut self.buffer,
+                channel,
+            };
+            cores.run(bytecode, &mut view, self_modify);
+        }
+    }
+
+    /// The number of bytes the processor engine's memory holds of the buffer. Layouts with more
+    /// channels only get the oldest frames that fit in its address space.
+    pub fn memory_len(&self) -> usize {
+        let frames = self
+            .buffer
+            .len()
+            .min(PROCESSOR_DATA_LIMIT / (C * BYTES_PER_SAMPLE));
+        frames * C * BYTES_PER_SAMPLE
+    }
+
+    /// Encode the buffer, oldest frame first, as interleaved little endian samples in the processor
+    /// engine's memory, as far as [Self::memory_len] reaches
+    pub fn write_to_memory(&self, memory: &mut [u8]) {
+        let frames = memory.chunks_exact_mut(C * BYTES_PER_SAMPLE);
+        for (frame, bytes) in self.buffer.iter().zip(frames) {
+            for (sample, bytes) in frame.iter().zip(bytes.chunks_exact_mut(BYTES_PER_SAMPLE)) {
+                bytes.copy_from_slice(&sample.to_sample::<i16>().to_le_bytes());
+            }
+        }
+    }
+
+    /// Decode the buffer back out of the processor engine's memory, see [Self::write_to_memory]
+    pub fn read_from_memory(&mut self, memory: &[u8]) {
+        let frames = memory.chunks_exact(C * BYTES_PER_SAMPLE);
+        for (frame, bytes) in self.buffer.iter_mut().zip(frames) {
+            for (sample, bytes) in frame.iter_mut().zip(bytes.chunks_exact(BYTES_PER_SAMPLE)) {
+                *sample = i16::from_le_bytes([bytes[0], bytes[1]]).to_sample::<f32>();
+            }
+        }
+    }
+}
+
+/// A [DelayBuffer] with as many channels as the host's chosen layout
+#[derive(Debug)]
+pub enum AnyDelayBuffer {
+    Mono(DelayBuffer<1>),
+    Stereo(DelayBuffer<2>),
+    Quad(DelayBuffer<4>),
+    Surround(DelayBuffer<6>),
+}
+
+/// Run `$body` with `$buffer` bound to whichever [DelayBuffer] is inside
+macro_rules! each_layout {
+    ($any:expr, $buffer:ident => $body:expr) => {
+        match $any {
+            AnyDelayBuffer::Mono($buffer) => $body,
+            AnyDelayBuffer::Stereo($buffer) => $body,
+            AnyDelayBuffer::Quad($buffer) => $body,
+            AnyDelayBuffer::Surround($buffer) => $body,
+        }
+    };
+}
+
+impl AnyDelayBuffer {
+    /// Layouts other than mono, quad and 5.1 fall back to stereo
+    pub fn new(channels: usize, len: usize) -> Self {
+        match channels {
+            1 => Self::Mono(DelayBuffer::new(len)),
+            4 => Self::Quad(DelayBuffer::new(len)),
+            6 => Self::Surround(DelayBuffer::new(len)),
+            _ => Self::Stereo(DelayBuffer::new(len)),
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        match self {
+            Self::Mono(_) => 1,
+            Self::Stereo(_) => 2,
+            Self::Quad(_) => 4,
+            Self::Surround(_) => 6,
+        }
+    }
+
+    pub fn ingest_audio(&mut self, audio: &mut Buffer) {
+        each_layout!(self, buffer => buffer.ingest_audio(audio))
+    }
+
+    pub fn write_to_audio(&self, audio: &mut Buffer) {
+        each_layout!(self, buffer => buffer.write_to_audio(audio))
+    }
+
+    pub fn run_vm(
+        &mut self,
+        cores: &mut Cores,
+        bytecode: &mut [u8],
+        self_modify: bool,
+        linked: bool,
+    ) {
+        each_layout!(self, buffer => buffer.run_vm(cores, bytecode, self_modify, linked))
+    }
+
+    pub fn memory_len(&self) -> usize {
+        each_layout!(self, buffer => buffer.memory_len())
+    }
+
+    pub fn write_to_memory(&self, memory: &mut [u8]) {
+        each_layout!(self, buffer => buffer.write_to_memory(memory))
+    }
+
+    pub fn read_from_memory(&mut self, memory: &[u8]) {
+        each_layout!(self, buffer => buffer.read_from_memory(memory))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lang::compile::{compile_for, CompileOptions, Target};
+
+    use super::AnyDelayBuffer;
+    use crate::{DELAY_BUFFER_LEN, PROCESSOR_CODE_LEN};
+
+    #[test]
+    fn test_programs_compile_for_every_layout() {
+        let gtch = lang::parse::parse("0>1 <2 3<>15 1+>0").unwrap();
+        for channels in [1, 2, 4, 6] {
+            let buffer = AnyDelayBuffer::new(channels, DELAY_BUFFER_LEN);
+            assert_eq!(buffer.channels(), channels);
+            let options = CompileOptions {
+                target: Target::Processor {
+                    data_len: buffer.memory_len(),
+                },
+                ..Default::default()
+            };
+            compile_for(&gtch, PROCESSOR_CODE_LEN, options).unwrap();
         }
     }
 }
@@ -2,7 +2,7 @@ mod analyzer;
 mod logo;
 mod program_editor;
 mod timer;
-use generate::generate;
+use generate::{generate, GeneratorConfig};
 use nih_plug::prelude::Editor;
 use nih_plug_vizia::vizia::prelude::*;
 use nih_plug_vizia::widgets::*;
@@ -10,8 +10,9 @@ use nih_plug_vizia::{assets, create_vizia_editor, ViziaState, ViziaTheming};
 use program_editor::ProgramEdit;
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::VmGlitchParams;
+use crate::{Engine, VmGlitchParams, GENERATOR_SEEDS, PROCESSOR_CODE_LEN};
 use analyzer::AnalyzerView;
 use lang::*;
 use logo::Logo;
@@ -23,6 +24,9 @@ struct VmData {
     params: Arc<VmGlitchParams>,
     from_vm_buffer: Arc<Mutex<Output<Vec<u8>>>>,
     to_vm_buffer: Arc<Mutex<Input<Vec<u8>>>>,
+    to_processor_buffer: Arc<Mutex<Input<Vec<u8>>>>,
+    /// The size of the audio region of the processor engine's memory, which depends on the channel layout
+    processor_data_len: Arc<AtomicUsize>,
     errs: String,
     counters: (Arc<AtomicUsize>, Arc<AtomicUsize>),
 }
@@ -39,18 +43,51 @@ impl Model for VmData {
                 match parsed {
                     Ok(gtch) => {
                         self.errs = "".to_string();
-                        let bytecode = lang::compile::compile(
+                        // Compile for both engines so switching doesn't need a fresh edit, but only
+                        // report errors from the one that's running
+                        let engine = self.params.engine.value();
+                        let optimize = self.params.optimize.value();
+                        let processor_code = lang::compile::compile_for(
+                            &gtch,
+                            PROCESSOR_CODE_LEN,
+                            lang::compile::CompileOptions {
+                                target: lang::compile::Target::Processor {
+                                    data_len: self
+                                        .processor_data_len
+                                        .load(std::sync::atomic::Ordering::Relaxed),
+                                },
+                                optimize,
+                            },
+                        );
+                        match processor_code {
+                            Ok(code) => {
+                                let mut guard = self.to_processor_buffer.lock().unwrap();
+                                trace!("->audio: publish processor code");
+                                guard.write(code);
+                            }
+                            Err(errs) if engine == Engine::Processor => {
+                                self.errs = format!("{:#?}", errs);
+                            }
+                            Err(_) => {}
+                        }
+                        let bytecode = lang::compile::compile_for(
                             &gtch,
                             self.from_vm_buffer
                                 .lock()
                                 .unwrap()
                                 .peek_output_buffer()
                                 .len(),
+                            lang::compile::CompileOptions {
+                                target: lang::compile::Target::Vm,
+                                optimize,
+                            },
                         );
                         let Ok(bytecode) = bytecode else {
                             let errs = bytecode.unwrap_err();
                             println!("{}", errs);
-                            self.errs = format!("{:#?}", errs);
+                            if engine == Engine::Classic {
+                                self.errs = format!("{:#?}", errs);
+                            }
                             return;
                         };
                         {
@@ -65,7 +102,39 @@ impl Model for VmData {
                 };
             }
             VmEvent::Gen => {
-                cx.emit(VmEvent::Edit(generate()));
+                let seed = match self.params.generator_seed.value() {
+                    0 => time_seed() % GENERATOR_SEEDS + 1,
+                    seed => seed,
+                };
+                let config = GeneratorConfig {
+                    seed: seed as u64,
+                    max_len: self
+                        .from_vm_buffer
+                        .lock()
+                        .unwrap()
+                        .peek_output_buffer()
+                        .len(),
+                    max_depth: self.params.generator_depth.value() as usize,
+                    ..Default::default()
+                };
+                cx.emit(VmEvent::Edit(generate(&config)));
+            }
+            VmEvent::Mutate => {
+                let code = self.params.code.lock().unwrap().clone();
+                match lang::parse::parse(&code) {
+                    Ok(gtch) => {
+                        let mutated = lang::mutate::mutate(&gtch, time_seed() as u64);
+                        cx.emit(VmEvent::Edit(lang::format::print(&mutated, &code)));
+                    }
+                    Err(errs) => self.errs = format!("{:#?}", errs),
+                }
+            }
+            VmEvent::Format => {
+                let code = self.params.code.lock().unwrap().clone();
+                match lang::format::format(&code) {
+                    Ok(formatted) => cx.emit(VmEvent::Edit(formatted)),
+                    Err(errs) => self.errs = format!("{:#?}", errs),
+                }
             }
         });
     }
@@ -74,6 +143,17 @@ impl Model for VmData {
 enum VmEvent {
     Edit(String),
     Gen,
+    Mutate,
+    Format,
+}
+
+/// A seed for presses which should each do something different. Time is random enough for that.
+fn time_seed() -> i32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    (nanos & i32::MAX as u32) as i32
 }
 // Makes sense to also define this here, makes it a bit easier to keep track of
 pub(crate) fn default_state() -> Arc<ViziaState> {
@@ -85,11 +165,14 @@ pub(crate) fn create(
     editor_state: Arc<ViziaState>,
     from_vm_buffer: Output<Vec<u8>>,
     to_vm_buffer: Input<Vec<u8>>,
+    to_processor_buffer: Input<Vec<u8>>,
+    processor_data_len: Arc<AtomicUsize>,
     counters: (Arc<AtomicUsize>, Arc<AtomicUsize>),
 ) -> Option<Box<dyn Editor>> {
     // need these to be Arc<Mutex<...>> only for the UI thread, there's no blocking from the audio thread.
     let from_vm_buffer = Arc::new(Mutex::new(from_vm_buffer));
     let to_vm_buffer = Arc::new(Mutex::new(to_vm_buffer));
+    let to_processor_buffer = Arc::new(Mutex::new(to_processor_buffer));
     create_vizia_editor(editor_state, ViziaTheming::Custom, move |cx, _| {
         assets::register_noto_sans_light(cx);
         assets::register_noto_sans_thin(cx);
@@ -99,6 +182,8 @@ pub(crate) fn create(
             params: params.clone(),
             from_vm_buffer: from_vm_buffer.clone(),
             to_vm_buffer: to_vm_buffer.clone(),
+            to_processor_buffer: to_processor_buffer.clone(),
+            processor_data_len: processor_data_len.clone(),
             errs: "".to_string(),
             counters: counters.clone(),
         }
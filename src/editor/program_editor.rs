@@ -26,9 +26,24 @@ impl ProgramEdit {
                     |cx| cx.emit(VmEvent::Gen),
                     |cx| nih_plug_vizia::vizia::views::Label::new(cx, "Generate"),
                 );
-                Textbox::new(cx, VmData::params.map(|p| p.code.lock().unwrap().clone()))
-                    .on_edit(|cx, s| cx.emit(VmEvent::Edit(s)))
-                    .min_width(Pixels(300.0));
+                Button::new(
+                    cx,
+                    |cx| cx.emit(VmEvent::Mutate),
+                    |cx| nih_plug_vizia::vizia::views::Label::new(cx, "Mutate"),
+                );
+                Button::new(
+                    cx,
+                    |cx| cx.emit(VmEvent::Format),
+                    |cx| nih_plug_vizia::vizia::views::Label::new(cx, "Format"),
+                );
+                // Multiline, so programs can be laid out with comments and labels
+                Textbox::new_multiline(
+                    cx,
+                    VmData::params.map(|p| p.code.lock().unwrap().clone()),
+                    true,
+                )
+                .on_edit(|cx, s| cx.emit(VmEvent::Edit(s)))
+                .min_width(Pixels(300.0));
             });
 
             nih_plug_vizia::vizia::views::Label::new(cx, VmData::errs).width(Pixels(300.0));
@@ -3,31 +3,124 @@ mod editor;
 mod threads;
 #[cfg(feature = "tracing")]
 mod trace;
-use delay_buffer::DelayBuffer;
+use delay_buffer::AnyDelayBuffer;
 use nih_plug::prelude::*;
 use nih_plug_vizia::ViziaState;
+use processor::{Processor, VmProcessor};
 use std::{
-    sync::{Arc, Mutex},
+    sync::{atomic::AtomicUsize, Arc, Mutex},
     thread::{self, spawn},
     time::Duration,
     vec,
 };
-use threads::{BytecodeComms, BytecodeThread};
-use tracing::{instrument, trace};
+use threads::{BytecodeComms, BytecodeThread, Tagged};
+use tracing::trace;
 use triple_buffer::{triple_buffer, Input, Output};
 use vm::backend::Backend;
-use vm::interpret::Vm;
+use vm::cores::{Cores, MAX_CORES};
+use vm::io::HostContext;
+use vm::sample::{Curve, SampleMode};
 
 pub type BytecodeUpdates = Vec<u8>;
 
+/// The length of the delay buffer in frames
+const DELAY_BUFFER_LEN: usize = 8192;
+
+/// The block length the CPU budget is given for. Other block lengths get a proportional budget, so the VM
+/// takes the same share of the audio thread however often it runs.
+const CPU_BUDGET_BLOCK_LEN: usize = 512;
+
+/// The size of the code region at the start of the processor engine's memory
+pub const PROCESSOR_CODE_LEN: usize = 512;
+
+/// The highest [VmGlitchParams::generator_seed], which seeds picked by the Generate button also stay within
+pub const GENERATOR_SEEDS: i32 = 99999;
+
+/// Mirrors [SampleMode] as a parameter
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleSource {
+    Left,
+    Right,
+    Mid,
+    Side,
+    Peak,
+    #[name = "RMS"]
+    Rms,
+}
+
+impl From<SampleSource> for SampleMode {
+    fn from(source: SampleSource) -> Self {
+        match source {
+            SampleSource::Left => SampleMode::Left,
+            SampleSource::Right => SampleMode::Right,
+            SampleSource::Mid => SampleMode::Mid,
+            SampleSource::Side => SampleMode::Side,
+            SampleSource::Peak => SampleMode::Peak,
+            SampleSource::Rms => SampleMode::Rms,
+        }
+    }
+}
+
+/// Mirrors [Curve] as a parameter
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleCurve {
+    Linear,
+    #[name = "μ-law"]
+    MuLaw,
+    #[name = "Logarithmic"]
+    Log,
+}
+
+impl From<SampleCurve> for Curve {
+    fn from(curve: SampleCurve) -> Self {
+        match curve {
+            SampleCurve::Linear => Curve::Linear,
+            SampleCurve::MuLaw => Curve::MuLaw,
+            SampleCurve::Log => Curve::Log,
+        }
+    }
+}
+
+/// How the program treats multichannel audio
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelMode {
+    /// Every op moves all channels together
+    Linked,
+    /// The program runs over each channel separately, sharing the CPU budget between them
+    #[name = "Per Channel"]
+    PerChannel,
+}
+
+/// Which engine runs the program over the delay buffer
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// The bytecode [Vm], modified by the bytecode thread
+    Classic,
+    /// The `processor` crate's engine, with code and audio in one memory. It only runs part of the
+    /// language, see [lang::assemble_processor::assemble_processor].
+    Processor,
+}
+
 #[derive(derive_more::Debug)]
 pub struct VmGlitch {
     #[debug(ignore)]
     params: Arc<VmGlitchParams>,
-    vm: Vm,
-    delay_buffer: DelayBuffer,
-    bytecode: Option<Output<Vec<u8>>>,
+    cores: Cores,
+    delay_buffer: AnyDelayBuffer,
+    bytecode: Option<Output<Tagged>>,
+    /// Sends the audio thread's self-modified bytecode back to the bytecode thread
+    bytecode_writeback: Option<Input<Tagged>>,
     bytecode_rate: Arc<AtomicF32>,
+    /// The engine that ran the last block, to notice when the parameter changes
+    engine: Engine,
+    #[debug(ignore)]
+    processor: VmProcessor,
+    /// The processor's code region followed by the delay buffer, see [AnyDelayBuffer::write_to_memory]
+    #[debug(ignore)]
+    processor_memory: Vec<u8>,
+    processor_code: Option<Output<Vec<u8>>>,
+    /// The size of the processor's data region for the current layout, which the editor compiles against
+    processor_data_len: Arc<AtomicUsize>,
 }
 
 #[derive(Params)]
@@ -39,6 +132,75 @@ pub struct VmGlitchParams {
     #[id = "bytecode_rate"]
     pub bytecode_rate: FloatParam,
 
+    /// Which engine runs the program
+    #[id = "engine"]
+    pub engine: EnumParam<Engine>,
+
+    /// Whether multichannel audio is processed linked or per channel
+    #[id = "channel_mode"]
+    pub channel_mode: EnumParam<ChannelMode>,
+
+    /// Seeds the VM's random number generator. Playback restarts from this seed on reset, so
+    /// renders are reproducible.
+    #[id = "seed"]
+    pub seed: IntParam,
+
+    /// How much audio the VM may touch per 512 frames of input, in thousands of frames. Keeps heavy
+    /// programs from overrunning the audio thread.
+    #[id = "cpu_budget"]
+    pub cpu_budget: IntParam,
+
+    /// The most instructions the VM runs per block, however cheap they are
+    #[id = "max_instructions"]
+    pub max_instructions: IntParam,
+
+    /// Whether the VM running on the audio also modifies its bytecode, on top of the bytecode
+    /// thread's own modifications
+    #[id = "self_modify_audio"]
+    pub self_modify_audio: BoolParam,
+
+    /// Whether programs are compiled with the ops that can't change the audio padded out, so they do
+    /// less work. The bytecode thread's self-modification sees the pads' bytes rather than the ops',
+    /// so patches saved before there was an optimizer can sound different, and it starts off. Takes
+    /// effect on the next edit.
+    #[id = "optimize"]
+    pub optimize: BoolParam,
+
+    /// How many VMs run over the delay buffer at once, each starting further through the program
+    #[id = "cores"]
+    pub cores: IntParam,
+
+    /// Whether each core runs its own region of the bytecode rather than all sharing one program
+    #[id = "split_program"]
+    pub split_program: BoolParam,
+
+    /// Where `~` sample ops take their sample from
+    #[id = "sample_source"]
+    pub sample_source: EnumParam<SampleSource>,
+
+    /// How `~` sample ops quantize their sample into a byte
+    #[id = "sample_curve"]
+    pub sample_curve: EnumParam<SampleCurve>,
+
+    /// Free parameters for programs to read from the I/O page with `$macro1` to `$macro4`
+    #[id = "macro1"]
+    pub macro1: FloatParam,
+    #[id = "macro2"]
+    pub macro2: FloatParam,
+    #[id = "macro3"]
+    pub macro3: FloatParam,
+    #[id = "macro4"]
+    pub macro4: FloatParam,
+
+    /// Seeds the Generate button, so a program can be generated again. At 0 each press picks its own
+    /// seed, which the program records in its first line.
+    #[id = "generator_seed"]
+    pub generator_seed: IntParam,
+
+    /// How deep the Generate button nests repeat groups
+    #[id = "generator_depth"]
+    pub generator_depth: IntParam,
+
     #[persist = "editor-state"]
     pub editor_state: Arc<ViziaState>,
 
@@ -54,12 +216,21 @@ impl Default for VmGlitch {
         #[cfg(feature = "tracing")]
         trace::setup();
 
+        let delay_buffer = AnyDelayBuffer::new(2, DELAY_BUFFER_LEN);
+        let data_len = delay_buffer.memory_len();
+
         Self {
             params: Arc::new(VmGlitchParams::default()),
-            vm: Vm::default(),
-            delay_buffer: DelayBuffer::new(8192),
+            cores: Cores::default(),
+            delay_buffer,
             bytecode: None,
+            bytecode_writeback: None,
             bytecode_rate: Arc::new(AtomicF32::new(0.5)),
+            engine: Engine::Classic,
+            processor: VmProcessor::new(PROCESSOR_CODE_LEN as u16, 1 << 18),
+            processor_memory: vec![0; PROCESSOR_CODE_LEN + data_len],
+            processor_code: None,
+            processor_data_len: Arc::new(AtomicUsize::new(data_len)),
         }
     }
 }
@@ -79,6 +250,60 @@ impl Default for VmGlitchParams {
                 },
             )
             .with_unit(" secs"),
+
+            engine: EnumParam::new("Engine", Engine::Classic),
+
+            channel_mode: EnumParam::new("Channels", ChannelMode::Linked),
+
+            seed: IntParam::new("Seed", 0, IntRange::Linear { min: 0, max: 9999 }),
+
+            cpu_budget: IntParam::new("CPU Budget", 256, IntRange::Linear { min: 1, max: 1024 })
+                .with_unit(" k frames"),
+
+            max_instructions: IntParam::new(
+                "Max Instructions",
+                512,
+                IntRange::Linear { min: 1, max: 4096 },
+            ),
+
+            self_modify_audio: BoolParam::new("Self-Modify on Audio", false),
+
+            optimize: BoolParam::new("Optimize", false),
+
+            cores: IntParam::new(
+                "Cores",
+                1,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_CORES as i32,
+                },
+            ),
+
+            split_program: BoolParam::new("Split Program", false),
+
+            sample_source: EnumParam::new("Sample Source", SampleSource::Mid),
+
+            sample_curve: EnumParam::new("Sample Curve", SampleCurve::Linear),
+
+            macro1: FloatParam::new("Macro 1", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 }),
+            macro2: FloatParam::new("Macro 2", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 }),
+            macro3: FloatParam::new("Macro 3", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 }),
+            macro4: FloatParam::new("Macro 4", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 }),
+
+            generator_seed: IntParam::new(
+                "Generator Seed",
+                0,
+                IntRange::Linear {
+                    min: 0,
+                    max: GENERATOR_SEEDS,
+                },
+            ),
+
+            generator_depth: IntParam::new(
+                "Generator Depth",
+                1,
+                IntRange::Linear { min: 0, max: 4 },
+            ),
         }
     }
 }
@@ -93,18 +318,43 @@ impl Plugin for VmGlitch {
 
     // The first audio IO layout is used as the default. The other layouts may be selected either
     // explicitly or automatically by the host or the user depending on the plugin API/backend.
-    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
-        main_input_channels: NonZeroU32::new(2),
-        main_output_channels: NonZeroU32::new(2),
-
-        aux_input_ports: &[],
-        aux_output_ports: &[],
-
-        // Individual ports and the layout as a whole can be named here. By default these names
-        // are generated as needed. This layout will be called 'Stereo', while a layout with
-        // only one input and output channel would be called 'Mono'.
-        names: PortNames::const_default(),
-    }];
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+
+            aux_input_ports: &[],
+            aux_output_ports: &[],
+
+            // Individual ports and the layout as a whole can be named here. By default these names
+            // are generated as needed. This layout will be called 'Stereo', while a layout with
+            // only one input and output channel would be called 'Mono'.
+            names: PortNames::const_default(),
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(1),
+            main_output_channels: NonZeroU32::new(1),
+            ..AudioIOLayout::const_default()
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(4),
+            main_output_channels: NonZeroU32::new(4),
+            names: PortNames {
+                layout: Some("Quad"),
+                ..PortNames::const_default()
+            },
+            ..AudioIOLayout::const_default()
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(6),
+            main_output_channels: NonZeroU32::new(6),
+            names: PortNames {
+                layout: Some("5.1"),
+                ..PortNames::const_default()
+            },
+            ..AudioIOLayout::const_default()
+        },
+    ];
 
     const MIDI_INPUT: MidiConfig = MidiConfig::None;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
@@ -126,38 +376,78 @@ impl Plugin for VmGlitch {
 
     fn initialize(
         &mut self,
-        _audio_io_layout: &AudioIOLayout,
+        audio_io_layout: &AudioIOLayout,
         _buffer_config: &BufferConfig,
         _context: &mut impl InitContext<Self>,
     ) -> bool {
         // Resize buffers and perform other potentially expensive initialization operations here.
         // The `reset()` function is always called right after this function. You can remove this
         // function if you do not need it.
+        let channels = audio_io_layout
+            .main_input_channels
+            .map_or(2, |channels| channels.get() as usize);
+        if channels != self.delay_buffer.channels() {
+            self.delay_buffer = AnyDelayBuffer::new(channels, DELAY_BUFFER_LEN);
+            let data_len = self.delay_buffer.memory_len();
+            self.processor_memory = vec![0; PROCESSOR_CODE_LEN + data_len];
+            self.processor_data_len
+                .store(data_len, std::sync::atomic::Ordering::Relaxed);
+        }
         true
     }
 
     fn reset(&mut self) {
         // Reset buffers and envelopes here. This can be called from the audio thread and may not
         // allocate. You can remove this function if you do not need it.
+        self.cores.set_seed(self.params.seed.value() as u64);
+        self.processor.reset();
     }
 
-    #[instrument(skip(self, buffer, _aux, _context))]
+    // Spans only while profiling, they aren't guaranteed not to allocate
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, buffer, _aux, context))
+    )]
     fn process(
         &mut self,
         buffer: &mut Buffer,
         _aux: &mut AuxiliaryBuffers,
-        _context: &mut impl ProcessContext<Self>,
+        context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
+        self.cores
+            .set_io(self.host_context(buffer, context.transport()).page());
         self.delay_buffer.ingest_audio(buffer);
 
-        if let Some(bytecode) = self.bytecode.as_mut() {
-            bytecode.update();
-            // run vm on audio without bytecode self-mod
-            self.vm.run(
-                bytecode.output_buffer(),
-                &mut self.delay_buffer.buffer,
-                false,
-            );
+        let seed = self.params.seed.value() as u64;
+        if seed != self.cores.seed() {
+            self.cores.set_seed(seed);
+        }
+        self.cores.set_count(self.params.cores.value() as usize);
+        self.cores.set_split(self.params.split_program.value());
+        // Per channel runs share the budget, so the cost doesn't scale with the channel count
+        let runs = match self.params.channel_mode.value() {
+            ChannelMode::Linked => 1,
+            ChannelMode::PerChannel => self.delay_buffer.channels(),
+        };
+        let max_cost = self.params.cpu_budget.value() as usize * 1024 * buffer.samples()
+            / CPU_BUDGET_BLOCK_LEN;
+        self.cores.set_max_cost(max_cost / runs);
+
+        self.cores
+            .set_max_instructions(self.params.max_instructions.value() as usize);
+        self.cores.set_sampling(
+            self.params.sample_source.value().into(),
+            self.params.sample_curve.value().into(),
+        );
+        self.processor.set_max_steps(max_cost);
+
+        let engine = self.params.engine.value();
+        if engine != self.engine {
+            self.switch_engine(engine);
+        }
+        match engine {
+            Engine::Classic => self.run_classic(),
+            Engine::Processor => self.run_processor(),
         }
 
         self.delay_buffer.write_to_audio(buffer);
@@ -176,29 +466,111 @@ impl Plugin for VmGlitch {
     fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
         let (tx, rx) = crossbeam_channel::bounded(100);
         let rate = Arc::clone(&self.bytecode_rate);
+        let params = self.params.clone();
         spawn(move || loop {
-            tx.send(threads::Message::ModBytecode).unwrap();
+            let seed = params.seed.value() as u64;
+            tx.send(threads::Message::ModBytecode { seed }).unwrap();
             thread::sleep(Duration::from_secs_f32(
                 rate.load(std::sync::atomic::Ordering::Relaxed),
             ));
         });
         let BytecodeComms {
             bc_in,
+            audio_in,
             ui_out,
             audio_out,
             video_out,
-        } = BytecodeThread::new(512, rx).spawn();
+        } = BytecodeThread::new(512, self.params.seed.value() as u64, rx).spawn();
         self.bytecode = Some(audio_out);
+        self.bytecode_writeback = Some(audio_in);
+        let (processor_in, processor_out) = triple_buffer(&vec![0; PROCESSOR_CODE_LEN]);
+        self.processor_code = Some(processor_out);
         editor::create(
             self.params.clone(),
             self.params.editor_state.clone(),
             ui_out,
             bc_in,
-            self.vm.ui_counters.clone(),
+            processor_in,
+            self.processor_data_len.clone(),
+            self.cores.ui_counters(),
         )
     }
 }
 
+impl VmGlitch {
+    /// What programs can read about the music this block, through the I/O page
+    fn host_context(&self, buffer: &Buffer, transport: &Transport) -> HostContext {
+        let input_level = buffer
+            .as_slice_immutable()
+            .iter()
+            .flat_map(|channel| channel.iter())
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        HostContext {
+            tempo: transport.tempo,
+            pos_beats: transport.pos_beats(),
+            time_sig_numerator: transport.time_sig_numerator,
+            playing: transport.playing,
+            input_level,
+            macros: [
+                self.params.macro1.value(),
+                self.params.macro2.value(),
+                self.params.macro3.value(),
+                self.params.macro4.value(),
+            ],
+        }
+    }
+
+    fn run_classic(&mut self) {
+        let Some(bytecode) = self.bytecode.as_mut() else {
+            return;
+        };
+        bytecode.update();
+        let self_modify = self.params.self_modify_audio.value();
+        let linked = self.params.channel_mode.value() == ChannelMode::Linked;
+        let tagged = bytecode.output_buffer();
+        self.delay_buffer
+            .run_vm(&mut self.cores, &mut tagged.bytecode, self_modify, linked);
+        if let (true, Some(writeback)) = (self_modify, self.bytecode_writeback.as_mut()) {
+            let written = writeback.input_buffer();
+            written.generation = tagged.generation;
+            written.bytecode.copy_from_slice(&tagged.bytecode);
+            writeback.publish();
+        }
+    }
+
+    fn run_processor(&mut self) {
+        let Some(code) = self.processor_code.as_mut() else {
+            return;
+        };
+        // The program may have rewritten its own code, so only replace it when there's a new one
+        if code.update() {
+            self.processor_memory[..PROCESSOR_CODE_LEN].copy_from_slice(code.output_buffer());
+        }
+        let (_, data) = self.processor_memory.split_at_mut(PROCESSOR_CODE_LEN);
+        self.delay_buffer.write_to_memory(data);
+        self.processor.run(&mut self.processor_memory);
+        self.delay_buffer
+            .read_from_memory(&self.processor_memory[PROCESSOR_CODE_LEN..]);
+    }
+
+    /// The delay buffer carries over between engines, so audio keeps playing through the switch. The
+    /// engine being switched to starts over from its latest program rather than wherever it left off.
+    fn switch_engine(&mut self, engine: Engine) {
+        match engine {
+            Engine::Classic => self.cores.set_seed(self.params.seed.value() as u64),
+            Engine::Processor => {
+                self.processor.reset();
+                if let Some(code) = self.processor_code.as_mut() {
+                    code.update();
+                    self.processor_memory[..PROCESSOR_CODE_LEN]
+                        .copy_from_slice(code.output_buffer());
+                }
+            }
+        }
+        self.engine = engine;
+    }
+}
+
 impl ClapPlugin for VmGlitch {
     const CLAP_ID: &'static str = "com.sandiskette.vm-glitch";
     const CLAP_DESCRIPTION: Option<&'static str> =
@@ -207,7 +579,12 @@ impl ClapPlugin for VmGlitch {
     const CLAP_SUPPORT_URL: Option<&'static str> = None;
 
     // Don't forget to change these features
-    const CLAP_FEATURES: &'static [ClapFeature] = &[ClapFeature::AudioEffect, ClapFeature::Stereo];
+    const CLAP_FEATURES: &'static [ClapFeature] = &[
+        ClapFeature::AudioEffect,
+        ClapFeature::Stereo,
+        ClapFeature::Mono,
+        ClapFeature::Surround,
+    ];
 }
 
 impl Vst3Plugin for VmGlitch {
@@ -6,35 +6,91 @@ use triple_buffer::{triple_buffer, Input, Output};
 use vm::{backend::NoopBackend, interpret::Vm};
 
 pub enum Message {
-    ModBytecode,
+    /// Run the program over itself, with the VM seeded from the current seed parameter
+    ModBytecode { seed: u64 },
+}
+
+/// Bytecode tagged with the generation of the UI program it was run from
+#[derive(Clone, Debug)]
+pub struct Tagged {
+    pub generation: u64,
+    pub bytecode: Vec<u8>,
+}
+
+impl Tagged {
+    fn new(size: usize) -> Self {
+        Self {
+            generation: 0,
+            bytecode: vec![0u8; size],
+        }
+    }
+}
+
+/// The bytecode thread's copy of the program. Each UI update and each mod run starts a new generation,
+/// so that audio write-backs of the bytecode they replaced can be told apart and dropped rather than
+/// undoing them.
+#[derive(Debug)]
+struct Program {
+    latest: Tagged,
+}
+
+impl Program {
+    fn update_from_ui(&mut self, bytecode: &[u8]) {
+        self.latest.generation += 1;
+        self.latest.bytecode.copy_from_slice(bytecode);
+    }
+
+    /// Run the program over itself on the bytecode thread
+    fn mod_run(&mut self, vm: &mut Vm) {
+        self.latest.generation += 1;
+        vm.run(&mut self.latest.bytecode, &mut NoopBackend, true);
+    }
+
+    /// Take the audio thread's self-modified bytecode, unless it was run from an older generation.
+    /// Returns whether it was taken.
+    fn write_back(&mut self, audio: &Tagged) -> bool {
+        if audio.generation < self.latest.generation {
+            return false;
+        }
+        self.latest.bytecode.copy_from_slice(&audio.bytecode);
+        true
+    }
 }
 
 pub struct BytecodeThread {
-    bytecode: Vec<u8>,
+    program: Program,
     vm: Vm,
     size: usize,
     rx: Receiver<Message>,
 }
 pub struct BytecodeComms {
     pub bc_in: Input<Vec<u8>>,
+    /// Write-back for bytecode the audio thread has modified itself, tagged with the generation it
+    /// got from `audio_out`
+    pub audio_in: Input<Tagged>,
     pub ui_out: Output<Vec<u8>>,
-    pub audio_out: Output<Vec<u8>>,
+    pub audio_out: Output<Tagged>,
     pub video_out: Output<Vec<u8>>,
 }
 
 impl BytecodeThread {
-    pub fn new(size: usize, msgs: Receiver<Message>) -> Self {
+    pub fn new(size: usize, seed: u64, msgs: Receiver<Message>) -> Self {
+        let mut vm = Vm::default();
+        vm.set_seed(seed);
         Self {
-            bytecode: vec![0u8; size],
-            vm: Vm::default(),
+            program: Program {
+                latest: Tagged::new(size),
+            },
+            vm,
             size,
             rx: msgs,
         }
     }
     pub fn spawn(mut self) -> BytecodeComms {
         let (from_ui_in, mut from_ui_out) = triple_buffer(&vec![0u8; self.size]);
+        let (from_audio_in, mut from_audio_out) = triple_buffer(&Tagged::new(self.size));
         let (mut to_ui_in, to_ui_out) = triple_buffer(&vec![0u8; self.size]);
-        let (mut to_audio_in, to_audio_out) = triple_buffer(&vec![0u8; self.size]);
+        let (mut to_audio_in, to_audio_out) = triple_buffer(&Tagged::new(self.size));
         let (mut to_video_in, to_video_out) = triple_buffer(&vec![0u8; self.size]);
         spawn(move || {
             tracy_client::set_thread_name!("bytecode mut loop");
@@ -42,27 +98,88 @@ impl BytecodeThread {
                 if from_ui_out.updated() {
                     trace!("UI->audio bytecode update");
                     let latest_ui_bytecode = from_ui_out.read();
-                    self.bytecode.copy_from_slice(latest_ui_bytecode.as_slice());
+                    self.program.update_from_ui(latest_ui_bytecode);
+                }
+                // The audio thread may still finish blocks on bytecode that has since been replaced
+                if from_audio_out.updated() {
+                    trace!("audio->bytecode self-modification update");
+                    if !self.program.write_back(from_audio_out.read()) {
+                        trace!("dropped stale audio write-back");
+                    }
                 }
                 // non-blocking recv
-                if let Ok(Message::ModBytecode) = self.rx.try_recv() {
+                if let Ok(Message::ModBytecode { seed }) = self.rx.try_recv() {
                     trace!("bytecode mod run");
-                    self.vm.run(&mut self.bytecode, &mut NoopBackend, true);
+                    // Reseeded like the audio thread's VM, only when the seed changes
+                    if seed != self.vm.seed() {
+                        self.vm.set_seed(seed);
+                    }
+                    self.program.mod_run(&mut self.vm);
                 }
-                to_ui_in.input_buffer().copy_from_slice(&self.bytecode);
+                let latest = &self.program.latest;
+                to_ui_in.input_buffer().copy_from_slice(&latest.bytecode);
                 to_ui_in.publish();
-                to_audio_in.input_buffer().copy_from_slice(&self.bytecode);
+                let to_audio = to_audio_in.input_buffer();
+                to_audio.generation = latest.generation;
+                to_audio.bytecode.copy_from_slice(&latest.bytecode);
                 to_audio_in.publish();
-                to_video_in.input_buffer().copy_from_slice(&self.bytecode);
+                to_video_in.input_buffer().copy_from_slice(&latest.bytecode);
                 to_video_in.publish();
             }
         });
 
         BytecodeComms {
             bc_in: from_ui_in,
+            audio_in: from_audio_in,
             ui_out: to_ui_out,
             audio_out: to_audio_out,
             video_out: to_video_out,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use vm::interpret::Vm;
+
+    use super::{Program, Tagged};
+
+    #[test]
+    fn test_stale_write_backs_are_dropped() {
+        let mut program = Program {
+            latest: Tagged::new(2),
+        };
+        program.update_from_ui(&[1, 1]);
+        // The audio thread runs generation 1 and modifies it
+        let stale = Tagged {
+            generation: program.latest.generation,
+            bytecode: vec![1, 2],
+        };
+        // The UI sends a new program before that write-back arrives
+        program.update_from_ui(&[5, 5]);
+        assert!(!program.write_back(&stale));
+        assert_eq!(program.latest.bytecode, [5, 5]);
+        // Once the audio thread runs the new program, its write-backs are taken again
+        let fresh = Tagged {
+            generation: program.latest.generation,
+            bytecode: vec![5, 6],
+        };
+        assert!(program.write_back(&fresh));
+        assert_eq!(program.latest.bytecode, [5, 6]);
+    }
+
+    #[test]
+    fn test_write_backs_dont_undo_mod_runs() {
+        let mut program = Program {
+            latest: Tagged::new(32),
+        };
+        program.update_from_ui(&[vm::op::Opcode::Random as u8; 32]);
+        let before_mod_run = program.latest.clone();
+        program.mod_run(&mut Vm::default());
+        let modified = program.latest.bytecode.clone();
+        assert_ne!(modified, before_mod_run.bytecode);
+        // The audio thread was still running the bytecode from before the mod run
+        assert!(!program.write_back(&before_mod_run));
+        assert_eq!(program.latest.bytecode, modified);
+    }
+}
@@ -0,0 +1,43 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use dasp::ring_buffer::Fixed;
+use vm::{
+    backend::{run_frames, Backend},
+    op::Op,
+    state::VmState,
+};
+
+/// The plugin's delay buffer size
+const FRAMES: usize = 8192;
+
+fn chunk_ops(c: &mut Criterion) {
+    let mut group = c.benchmark_group("chunk ops");
+    let state = VmState::default();
+    let mut bytecode = [0; 512];
+    // Start the ring part way through, so chunks straddle the wrap
+    let ring = || Fixed::from_raw_parts(FRAMES / 3, vec![[0.5f32, -0.5]; FRAMES]);
+
+    for (name, op) in [
+        ("copy", Op::Copy as fn(usize, usize) -> Op),
+        ("swap", Op::Swap),
+    ] {
+        let mut buffer = ring();
+        group.bench_function(BenchmarkId::new(name, "slices"), |b| {
+            b.iter(|| buffer.run(&mut bytecode, op(black_box(3), black_box(12)), &state))
+        });
+        let mut buffer = ring();
+        group.bench_function(BenchmarkId::new(name, "frames"), |b| {
+            b.iter(|| {
+                run_frames(
+                    &mut buffer,
+                    &mut bytecode,
+                    op(black_box(3), black_box(12)),
+                    &state,
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, chunk_ops);
+criterion_main!(benches);
@@ -1,10 +1,14 @@
 use dasp::ring_buffer;
-use numquant::linear;
 
 use crate::{op::Op, state::VmState, REGISTER_COUNT};
 
 pub trait Backend {
     fn run(&mut self, bytecode: &mut [u8], op: Op, vm_state: &VmState);
+
+    /// The number of frames in each of the [REGISTER_COUNT] chunks, used to work out what ops cost
+    fn chunk_size(&self) -> usize {
+        0
+    }
 }
 
 pub struct NoopBackend;
@@ -13,53 +17,353 @@ impl Backend for NoopBackend {
     fn run(&mut self, _bytecode: &mut [u8], _op: Op, _vm_state: &VmState) {}
 }
 
-impl Backend for ring_buffer::Fixed<Vec<[f32; 2]>> {
+/// Random access to frames of `C` channels of audio, with indices wrapping around the end like [ring_buffer::Fixed]
+pub trait Frames<const C: usize> {
+    fn frame_count(&self) -> usize;
+    fn get(&self, i: usize) -> &[f32; C];
+    fn get_mut(&mut self, i: usize) -> &mut [f32; C];
+}
+
+impl<const C: usize> Frames<C> for ring_buffer::Fixed<Vec<[f32; C]>> {
+    fn frame_count(&self) -> usize {
+        self.len()
+    }
+
+    fn get(&self, i: usize) -> &[f32; C] {
+        ring_buffer::Fixed::get(self, i)
+    }
+
+    fn get_mut(&mut self, i: usize) -> &mut [f32; C] {
+        ring_buffer::Fixed::get_mut(self, i)
+    }
+}
+
+/// A single channel of a multichannel buffer, so the VM can run over each channel separately
+pub struct ChannelView<'a, const C: usize> {
+    pub buffer: &'a mut ring_buffer::Fixed<Vec<[f32; C]>>,
+    pub channel: usize,
+}
+
+impl<const C: usize> Frames<1> for ChannelView<'_, C> {
+    fn frame_count(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn get(&self, i: usize) -> &[f32; 1] {
+        std::array::from_ref(&self.buffer.get(i)[self.channel])
+    }
+
+    fn get_mut(&mut self, i: usize) -> &mut [f32; 1] {
+        std::array::from_mut(&mut self.buffer.get_mut(i)[self.channel])
+    }
+}
+
+impl<const C: usize> Backend for ring_buffer::Fixed<Vec<[f32; C]>> {
+    fn chunk_size(&self) -> usize {
+        self.len() / REGISTER_COUNT
+    }
+
     fn run(&mut self, bytecode: &mut [u8], op: Op, vm_state: &VmState) {
-        let chunk_size_audio = self.len() / REGISTER_COUNT;
+        let chunk_size_audio = self.chunk_size();
+        // Whole chunk moves have a fast path over the ring's contiguous slices
         match op {
             Op::Copy(from_idx, to_idx) => {
-                let chunk_start = from_idx * chunk_size_audio;
-                let chunk_end = chunk_start + chunk_size_audio;
-                for (i, frame) in (chunk_start..chunk_end).enumerate() {
-                    let from_frame = *self.get(frame);
-                    let to_frame = self.get_mut((to_idx * chunk_size_audio) + i);
-                    to_frame[0] = from_frame[0];
-                    to_frame[1] = from_frame[1];
-
-                    #[cfg(feature = "tracing")]
-                    tracy_client::plot!("audio Op::Copy", 1.0);
-                }
-            }
-            Op::Sample(i) => {
-                let frame = self.get(i);
-                let mut sample = frame[0] + frame[1];
-                sample /= self.len() as f32;
-                bytecode[vm_state.pc] = linear::quantize(sample as f64, -1.0..1.0, 255);
+                copy_chunk(
+                    self,
+                    from_idx * chunk_size_audio,
+                    to_idx * chunk_size_audio,
+                    chunk_size_audio,
+                );
                 #[cfg(feature = "tracing")]
-                tracy_client::plot!("audio Op::Sample", 1.0);
+                tracy_client::plot!("audio Op::Copy", 1.0);
             }
             Op::Swap(i, j) => {
-                for offset in 0..chunk_size_audio {
-                    let j_frame = *self.get((j * chunk_size_audio) + offset);
-                    let i_frame = self.get_mut((i * chunk_size_audio) + offset);
-                    let i_backup = *i_frame;
-                    i_frame[0] = j_frame[0];
-                    i_frame[1] = j_frame[1];
-                    let j_frame = self.get_mut((j * chunk_size_audio) + offset);
-                    j_frame[0] = i_backup[0];
-                    j_frame[1] = i_backup[1];
-                }
-
+                swap_chunk(
+                    self,
+                    i * chunk_size_audio,
+                    j * chunk_size_audio,
+                    chunk_size_audio,
+                );
                 #[cfg(feature = "tracing")]
                 tracy_client::plot!("audio Op::Swap", 1.0);
             }
-            _ => {}
+            op => run_frames(self, bytecode, op, vm_state),
+        }
+        play(self, vm_state);
+    }
+}
+
+impl<const C: usize> Backend for ChannelView<'_, C> {
+    fn chunk_size(&self) -> usize {
+        self.buffer.len() / REGISTER_COUNT
+    }
+
+    fn run(&mut self, bytecode: &mut [u8], op: Op, vm_state: &VmState) {
+        run_frames(self, bytecode, op, vm_state);
+        play(self, vm_state);
+    }
+}
+
+/// How many logical indices on from `from` and `to` both stay within one of the ring's two slices
+fn run_len(from: usize, to: usize, split: usize, len: usize, remaining: usize) -> usize {
+    let to_boundary = |i: usize| if i < split { split - i } else { len - i };
+    to_boundary(from).min(to_boundary(to)).min(remaining)
+}
+
+/// Copy `n` frames from logical index `from` to `to`, a contiguous run at a time
+fn copy_chunk<T: Copy>(ring: &mut ring_buffer::Fixed<Vec<T>>, from: usize, to: usize, n: usize) {
+    if from == to {
+        return;
+    }
+    let len = ring.len();
+    let (first, second) = ring.slices_mut();
+    let split = first.len();
+    let mut done = 0;
+    while done < n {
+        let (a, b) = (from + done, to + done);
+        let run = run_len(a, b, split, len, n - done);
+        match (a < split, b < split) {
+            (true, true) => first.copy_within(a..a + run, b),
+            (false, false) => second.copy_within(a - split..a - split + run, b - split),
+            (true, false) => second[b - split..b - split + run].copy_from_slice(&first[a..a + run]),
+            (false, true) => first[b..b + run].copy_from_slice(&second[a - split..a - split + run]),
+        }
+        done += run;
+    }
+}
+
+/// Swap the `n` frames at logical indices `i` and `j`, which must not partly overlap
+fn swap_chunk<T>(ring: &mut ring_buffer::Fixed<Vec<T>>, i: usize, j: usize, n: usize) {
+    if i == j {
+        return;
+    }
+    let len = ring.len();
+    let (first, second) = ring.slices_mut();
+    let split = first.len();
+    let swap_within = |slice: &mut [T], a: usize, b: usize, run: usize| {
+        let (low, high) = (a.min(b), a.max(b));
+        let (front, back) = slice.split_at_mut(high);
+        front[low..low + run].swap_with_slice(&mut back[..run]);
+    };
+    let mut done = 0;
+    while done < n {
+        let (a, b) = (i + done, j + done);
+        let run = run_len(a, b, split, len, n - done);
+        match (a < split, b < split) {
+            (true, true) => swap_within(first, a, b, run),
+            (false, false) => swap_within(second, a - split, b - split, run),
+            (true, false) => {
+                first[a..a + run].swap_with_slice(&mut second[b - split..b - split + run])
+            }
+            (false, true) => {
+                first[b..b + run].swap_with_slice(&mut second[a - split..a - split + run])
+            }
         }
+        done += run;
+    }
+}
+
+/// Play the frame under the PC, by copying it to the frame being output
+fn play<const C: usize>(frames: &mut impl Frames<C>, vm_state: &VmState) {
+    let frame = *frames.get(vm_state.pc);
+    *frames.get_mut(vm_state.buf_index) = frame;
+}
+
+/// Run an op over any [Frames], a frame at a time. Every channel of a frame is treated alike.
+///
+/// Doesn't play the current frame, unlike [Backend::run]. Public so the slice fast path can be benchmarked against it.
+pub fn run_frames<const C: usize>(
+    frames: &mut impl Frames<C>,
+    bytecode: &mut [u8],
+    op: Op,
+    vm_state: &VmState,
+) {
+    let chunk_size_audio = frames.frame_count() / REGISTER_COUNT;
+    match op {
+        Op::Copy(from_idx, to_idx) => {
+            let chunk_start = from_idx * chunk_size_audio;
+            let chunk_end = chunk_start + chunk_size_audio;
+            for (i, frame) in (chunk_start..chunk_end).enumerate() {
+                let from_frame = *frames.get(frame);
+                *frames.get_mut((to_idx * chunk_size_audio) + i) = from_frame;
+            }
+
+            #[cfg(feature = "tracing")]
+            tracy_client::plot!("audio Op::Copy", 1.0);
+        }
+        Op::Sample(i) => {
+            let chunk_start = i * chunk_size_audio;
+            let chunk =
+                (chunk_start..chunk_start + chunk_size_audio.max(1)).map(|n| *frames.get(n));
+            let sample = vm_state.sample_mode.measure(chunk);
+            bytecode[vm_state.pc] = vm_state.curve.quantize(sample);
+            #[cfg(feature = "tracing")]
+            tracy_client::plot!("audio Op::Sample", 1.0);
+        }
+        Op::Swap(i, j) => {
+            for offset in 0..chunk_size_audio {
+                let j_frame = *frames.get((j * chunk_size_audio) + offset);
+                let i_frame = frames.get_mut((i * chunk_size_audio) + offset);
+                let i_backup = *i_frame;
+                *i_frame = j_frame;
+                *frames.get_mut((j * chunk_size_audio) + offset) = i_backup;
+            }
+
+            #[cfg(feature = "tracing")]
+            tracy_client::plot!("audio Op::Swap", 1.0);
+        }
+        Op::Reverse(i) => {
+            let chunk_start = i * chunk_size_audio;
+            for offset in 0..chunk_size_audio / 2 {
+                let back = chunk_start + chunk_size_audio - 1 - offset;
+                let back_frame = *frames.get(back);
+                let front_frame = frames.get_mut(chunk_start + offset);
+                let front_backup = *front_frame;
+                *front_frame = back_frame;
+                *frames.get_mut(back) = front_backup;
+            }
+
+            #[cfg(feature = "tracing")]
+            tracy_client::plot!("audio Op::Reverse", 1.0);
+        }
+        Op::HalfSpeed(i, j) => {
+            // Walk backwards so that `i == j` never reads a frame it has already written
+            for offset in (0..chunk_size_audio).rev() {
+                let from_frame = *frames.get((i * chunk_size_audio) + offset / 2);
+                *frames.get_mut((j * chunk_size_audio) + offset) = from_frame;
+            }
+
+            #[cfg(feature = "tracing")]
+            tracy_client::plot!("audio Op::HalfSpeed", 1.0);
+        }
+        Op::DoubleSpeed(i, j) => {
+            let half = chunk_size_audio.div_ceil(2);
+            let to_start = j * chunk_size_audio;
+            for offset in 0..half {
+                let from_frame = *frames.get((i * chunk_size_audio) + offset * 2);
+                *frames.get_mut(to_start + offset) = from_frame;
+            }
+            // Play it again
+            for offset in 0..chunk_size_audio - half {
+                let frame = *frames.get(to_start + offset);
+                *frames.get_mut(to_start + half + offset) = frame;
+            }
+
+            #[cfg(feature = "tracing")]
+            tracy_client::plot!("audio Op::DoubleSpeed", 1.0);
+        }
+        Op::Mix(mix, i, j) => {
+            for offset in 0..chunk_size_audio {
+                let i_frame = *frames.get((i * chunk_size_audio) + offset);
+                let j_frame = frames.get_mut((j * chunk_size_audio) + offset);
+                for (j_sample, i_sample) in j_frame.iter_mut().zip(i_frame) {
+                    *j_sample = mix.audio(i_sample, *j_sample);
+                }
+            }
+
+            #[cfg(feature = "tracing")]
+            tracy_client::plot!("audio Op::Mix", 1.0);
+        }
+        Op::Crush(i, bits) => {
+            // Half the number of steps, as they're spread over -1..1
+            let scale = ((1u32 << bits) - 1) as f32 / 2.0;
+            for offset in 0..chunk_size_audio {
+                let frame = frames.get_mut((i * chunk_size_audio) + offset);
+                for sample in frame.iter_mut() {
+                    *sample = ((sample.clamp(-1.0, 1.0) + 1.0) * scale).round() / scale - 1.0;
+                }
+            }
+
+            #[cfg(feature = "tracing")]
+            tracy_client::plot!("audio Op::Crush", 1.0);
+        }
+        Op::Decimate(i, hold) => {
+            let chunk_start = i * chunk_size_audio;
+            for offset in 0..chunk_size_audio {
+                let held = *frames.get(chunk_start + offset - offset % hold);
+                *frames.get_mut(chunk_start + offset) = held;
+            }
+
+            #[cfg(feature = "tracing")]
+            tracy_client::plot!("audio Op::Decimate", 1.0);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dasp::ring_buffer::Fixed;
+
+    use proptest::prelude::*;
+
+    use super::{run_frames, Backend, ChannelView};
+    use crate::{op::Op, state::VmState, REGISTER_COUNT};
+
+    proptest! {
+        #[test]
+        fn test_slice_fast_path_matches_frame_path(
+            len in (1..64usize).prop_map(|n| n * REGISTER_COUNT),
+            first in 0..1024usize,
+            swap: bool,
+            i in 0..REGISTER_COUNT,
+            j in 0..REGISTER_COUNT,
+        ) {
+            let data = (0..len).map(|n| [n as f32, -(n as f32)]).collect::<Vec<_>>();
+            let mut fast = Fixed::from_raw_parts(first % len, data);
+            let mut slow = fast.clone();
+            let op = || if swap { Op::Swap(i, j) } else { Op::Copy(i, j) };
+            let mut bytecode = [0; 32];
+            fast.run(&mut bytecode, op(), &VmState::default());
+            run_frames(&mut slow, &mut bytecode, op(), &VmState::default());
+            prop_assert!(fast.iter().eq(slow.iter()));
+        }
+    }
+
+    #[test]
+    fn test_channel_view_only_touches_its_channel() {
+        let mut buffer = Fixed::from(
+            (0..REGISTER_COUNT * 2)
+                .map(|i| [i as f32, -(i as f32), 0.5])
+                .collect::<Vec<_>>(),
+        );
+        let mut bytecode = [0; 32];
+        let mut view = ChannelView {
+            buffer: &mut buffer,
+            channel: 1,
+        };
+        view.run(&mut bytecode, Op::Copy(0, 1), &VmState::default());
+        // Chunk 0 is frames 0 and 1, copied over chunk 1 in the second channel only
+        assert_eq!(buffer.get(2), &[2.0, 0.0, 0.5]);
+        assert_eq!(buffer.get(3), &[3.0, -1.0, 0.5]);
+    }
+
+    #[test]
+    fn test_reverse_and_speed_changes() {
+        // Four frame chunks, each frame holding its index
+        let run = |op| {
+            let mut buffer = Fixed::from((0..64).map(|i| [i as f32]).collect::<Vec<_>>());
+            run_frames(&mut buffer, &mut [0; 32], op, &VmState::default());
+            let frames = buffer.iter().skip(4).take(8);
+            frames.map(|[s]| *s as usize).collect::<Vec<_>>()
+        };
+        assert_eq!(run(Op::Reverse(1)), [7, 6, 5, 4, 8, 9, 10, 11]);
+        assert_eq!(run(Op::HalfSpeed(1, 2)), [4, 5, 6, 7, 4, 4, 5, 5]);
+        assert_eq!(run(Op::DoubleSpeed(1, 2)), [4, 5, 6, 7, 4, 6, 4, 6]);
+        // In place, without reading frames already written
+        assert_eq!(run(Op::HalfSpeed(1, 1)), [4, 4, 5, 5, 8, 9, 10, 11]);
+        assert_eq!(run(Op::DoubleSpeed(1, 1)), [4, 6, 4, 6, 8, 9, 10, 11]);
+    }
 
-        let chans = self.get(vm_state.pc);
-        let (left, right) = (chans[0], chans[1]);
-        let chans = self.get_mut(vm_state.buf_index);
-        chans[0] = left;
-        chans[1] = right;
+    #[test]
+    fn test_mono_and_surround_buffers() {
+        let mut mono = Fixed::from((0..64).map(|i| [i as f32]).collect::<Vec<_>>());
+        let mut surround = Fixed::from(vec![[0.25; 6]; 64]);
+        *surround.get_mut(4) = [1.0; 6];
+        let mut bytecode = [0; 32];
+        mono.run(&mut bytecode, Op::Reverse(1), &VmState::default());
+        surround.run(&mut bytecode, Op::Swap(0, 1), &VmState::default());
+        assert_eq!(mono.get(4), &[7.0]);
+        assert_eq!(surround.get(0), &[1.0; 6]);
     }
 }
@@ -0,0 +1,218 @@
+use std::sync::{atomic::AtomicUsize, Arc};
+
+use crate::{
+    backend::Backend,
+    interpret::Vm,
+    io::IO_PAGE_LEN,
+    sample::{Curve, SampleMode},
+};
+
+/// The most [Vm]s that can run at once
+pub const MAX_CORES: usize = 8;
+
+/// Several [Vm]s running interleaved over the same audio, one instruction each in turn.
+///
+/// Core `k` of `n` writes its output `k / n` of the way through the buffer, so the cores drift in and out of phase
+/// with each other. They either share the whole bytecode, starting that far through it too, or each get their own
+/// equal region of it and start at its top.
+#[derive(Clone, Debug)]
+pub struct Cores {
+    vms: [Vm; MAX_CORES],
+    count: usize,
+    split: bool,
+    seed: u64,
+    /// The budgets for all the cores together, split between however many are running when a run starts
+    max_instructions: usize,
+    max_cost: usize,
+}
+
+impl Cores {
+    pub fn new(max_instructions: usize) -> Self {
+        Self {
+            vms: std::array::from_fn(|_| Vm::new(max_instructions)),
+            count: 1,
+            split: false,
+            seed: 0,
+            max_instructions,
+            max_cost: usize::MAX,
+        }
+    }
+
+    /// Run every core's program once, interleaved. Stops when they've all finished.
+    pub fn run<B: Backend>(&mut self, bytecode: &mut [u8], backend: &mut B, self_modify: bool) {
+        let count = self.count;
+        let region_len = self.region_len(bytecode.len());
+        for (k, vm) in self.vms[..count].iter_mut().enumerate() {
+            vm.set_max_instructions(self.max_instructions / count);
+            vm.set_max_cost(self.max_cost / count);
+            let buf_index = k * bytecode.len() / count;
+            // Split cores start at the top of their own region instead
+            let pc = if self.split { 0 } else { buf_index };
+            vm.start(pc, buf_index);
+        }
+
+        let mut running = true;
+        while running {
+            running = false;
+            for (k, vm) in self.vms[..count].iter_mut().enumerate() {
+                let region = if self.split {
+                    &mut bytecode[k * region_len..(k + 1) * region_len]
+                } else {
+                    &mut *bytecode
+                };
+                if vm.is_running(region.len()) {
+                    vm.step_once(region, backend, self_modify);
+                    running = true;
+                }
+            }
+        }
+    }
+
+    /// The length of each core's program
+    fn region_len(&self, bytecode_len: usize) -> usize {
+        if self.split {
+            bytecode_len / self.count
+        } else {
+            bytecode_len
+        }
+    }
+
+    /// Set how many cores run, from 1 to [MAX_CORES]
+    pub fn set_count(&mut self, count: usize) {
+        self.count = count.clamp(1, MAX_CORES);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Whether each core runs its own region of the bytecode rather than all sharing it
+    pub fn set_split(&mut self, split: bool) {
+        self.split = split;
+    }
+
+    /// Set the instruction budget for all the cores together
+    pub fn set_max_instructions(&mut self, max_instructions: usize) {
+        self.max_instructions = max_instructions;
+    }
+
+    /// Set the cost budget for all the cores together, see [Vm::set_max_cost]
+    pub fn set_max_cost(&mut self, max_cost: usize) {
+        self.max_cost = max_cost;
+    }
+
+    /// Reseed every core, each with its own sequence
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        for (k, vm) in self.vms.iter_mut().enumerate() {
+            vm.set_seed(seed.wrapping_add(k as u64));
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn set_sampling(&mut self, mode: SampleMode, curve: Curve) {
+        self.vms
+            .iter_mut()
+            .for_each(|vm| vm.set_sampling(mode, curve));
+    }
+
+    /// Give every core the same I/O page
+    pub fn set_io(&mut self, page: [u8; IO_PAGE_LEN]) {
+        self.vms.iter_mut().for_each(|vm| vm.set_io(page));
+    }
+
+    /// The cores which are running
+    pub fn cores(&self) -> &[Vm] {
+        &self.vms[..self.count]
+    }
+
+    /// The first core's PC and buffer index, for the UI
+    pub fn ui_counters(&self) -> (Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        self.vms[0].ui_counters.clone()
+    }
+}
+
+impl Default for Cores {
+    fn default() -> Self {
+        Self::new(512)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dasp::ring_buffer::Fixed;
+
+    use super::Cores;
+    use crate::{backend::NoopBackend, op::Opcode};
+
+    const REVERSE: u8 = Opcode::Reverse as u8;
+
+    #[test]
+    fn test_cores_start_out_of_phase() {
+        let mut bytecode = [0; 64];
+        let mut cores = Cores::default();
+        cores.set_count(4);
+        cores.set_max_instructions(4);
+        cores.run(&mut bytecode, &mut NoopBackend, false);
+        let pcs = cores
+            .cores()
+            .iter()
+            .map(|vm| vm.state().buf_index)
+            .collect::<Vec<_>>();
+        // Each core gets a quarter of the instruction budget, and starts a quarter further through
+        assert_eq!(pcs, vec![2, 18, 34, 50]);
+    }
+
+    #[test]
+    fn test_split_cores_stay_in_their_region() {
+        // The second core reverses the first chunk of its own region, not of the whole bytecode
+        let mut bytecode = [0; 64];
+        bytecode[32..34].copy_from_slice(&[REVERSE, 0]);
+        let mut cores = Cores::new(64);
+        cores.set_count(2);
+        cores.set_split(true);
+        cores.run(&mut bytecode, &mut NoopBackend, true);
+        assert_eq!(bytecode[32..34], [0, REVERSE]);
+        let [first, second] = cores.cores() else {
+            panic!("expected two cores");
+        };
+        assert_eq!(first.state().pc, 32);
+        assert_eq!(second.state().pc, 32);
+    }
+
+    #[test]
+    fn test_split_cores_write_their_own_frames() {
+        // Both cores run the same program, a Noop for each chunk of their region
+        let mut bytecode = [0; 64];
+        let mut cores = Cores::new(64);
+        cores.set_count(2);
+        cores.set_split(true);
+        cores.run(&mut bytecode, &mut NoopBackend, false);
+        let [first, second] = cores.cores() else {
+            panic!("expected two cores");
+        };
+        // Each wrote a frame per op, starting half the buffer apart
+        assert_eq!(first.state().buf_index, 32);
+        assert_eq!(second.state().buf_index, 64);
+    }
+
+    #[test]
+    fn test_budget_is_shared() {
+        let mut bytecode = [Opcode::Copy as u8, 0, 1].repeat(64);
+        let mut buffer = Fixed::from(vec![[0.0; 2]; 1024]);
+        let mut cores = Cores::new(usize::MAX);
+        // The budget is split when the cores run, so the count can be set after it
+        cores.set_max_cost(3000);
+        cores.set_count(3);
+        cores.run(&mut bytecode, &mut buffer, false);
+        let total = cores
+            .cores()
+            .iter()
+            .map(|vm| vm.state().cost_for_run)
+            .sum::<usize>();
+        assert!(total <= 3000, "cost {total} over budget");
+    }
+}
@@ -2,12 +2,14 @@ use std::sync::{atomic::AtomicUsize, Arc};
 
 use crate::{
     backend::{Backend, NoopBackend},
-    op::{Op, Opcode},
-    state::VmState,
+    io::IO_PAGE_LEN,
+    op::{Mix, Op, Opcode},
+    rng::Rng,
+    sample::{Curve, SampleMode},
+    state::{VmState, STACK_DEPTH},
 };
 use dasp::*;
 use ring_buffer::Fixed;
-use tracing::instrument;
 
 pub type RawBuffer<'a> = &'a mut Fixed<Vec<[f32; 2]>>;
 
@@ -20,59 +22,147 @@ pub struct Vm {
     ///
     /// When this is reached the VM will halt early to avoid ever blocking/hanging the audio thread.
     max_instructions: usize,
+    /// The maximum total [Op::cost] to run.
+    ///
+    /// Like [Self::max_instructions] but weighted by how much audio each op touches, so that it tracks the actual time spent on the audio thread.
+    /// An op which would take the run over budget is not run at all.
+    max_cost: usize,
+    /// The seed [VmState::rng] was last reseeded with
+    seed: u64,
     state: VmState,
     pub ui_counters: (Arc<AtomicUsize>, Arc<AtomicUsize>),
 }
 
 impl Vm {
+    pub fn new(max_instructions: usize) -> Self {
+        Self {
+            max_instructions,
+            max_cost: usize::MAX,
+            seed: 0,
+            state: VmState::default(),
+            ui_counters: (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0))),
+        }
+    }
+
     /// Run the Vm's current bytecode.
     ///
     /// Modifies the audio buffer and the bytecode simultaneously.
     pub fn run<B: Backend>(&mut self, bytecode: &mut [u8], backend: &mut B, self_modify: bool) {
-        self.reset();
-        while self.state.pc < bytecode.len() && self.state.total_for_run <= self.max_instructions {
-            self.step(bytecode, backend, self_modify);
-            self.notify();
+        self.start(0, 0);
+        while self.is_running(bytecode.len()) {
+            self.step_once(bytecode, backend, self_modify);
         }
     }
 
-    #[instrument(skip(self, bytecode, backend))]
+    /// Start a run from `pc` in the bytecode and `buf_index` in the audio buffer, to be driven a step at a time
+    /// with [Self::step_once]
+    pub fn start(&mut self, pc: usize, buf_index: usize) {
+        self.reset();
+        self.state.pc = pc;
+        self.state.buf_index = buf_index;
+    }
+
+    /// Whether the current run has anything left to do
+    pub fn is_running(&self, bytecode_len: usize) -> bool {
+        self.state.pc < bytecode_len
+            && self.state.total_for_run <= self.max_instructions
+            && !self.state.halted
+    }
+
+    /// Run the next instruction of the current run. Check [Self::is_running] first.
+    pub fn step_once<B: Backend>(
+        &mut self,
+        bytecode: &mut [u8],
+        backend: &mut B,
+        self_modify: bool,
+    ) {
+        self.step(bytecode, backend, self_modify);
+        self.notify();
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, bytecode, backend))
+    )]
     fn step<B: Backend>(&mut self, bytecode: &mut [u8], backend: &mut B, self_modify: bool) {
         #[cfg(feature = "tracing")]
         {
             tracy_client::plot!("PC", self.state.pc as f64);
             tracy_client::plot!("buf_idx", self.state.buf_index as f64);
             tracy_client::plot!("total_for_run", self.state.total_for_run as f64);
+            tracy_client::plot!("cost_for_run", self.state.cost_for_run as f64);
         }
 
-        let op = self.parse_op(bytecode, REGISTER_COUNT);
-        if let Some(op) = op {
-            self.run_op(op, bytecode, backend, self_modify);
+        let op = self.parse_op(bytecode);
+
+        let chunk_size = backend.chunk_size().max(bytecode.len() / REGISTER_COUNT);
+        let cost = op
+            .as_ref()
+            .map_or(1, |op| op.cost(chunk_size, self.state.sample_mode));
+        if self.state.cost_for_run + cost > self.max_cost {
+            self.state.halted = true;
+            return;
         }
+        self.state.cost_for_run += cost;
 
-        self.increment()
+        let advance = match op {
+            Some(op) => self.run_op(op, bytecode, backend, self_modify),
+            None => true,
+        };
+
+        self.increment(advance)
     }
 
     /// Parses the current [Op] and its args
-    #[instrument(skip(self, bytecode))]
-    fn parse_op(&mut self, bytecode: &mut [u8], registers: usize) -> Option<Op> {
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, bytecode)))]
+    fn parse_op(&mut self, bytecode: &mut [u8]) -> Option<Op> {
         let byte = *bytecode.get(self.state.pc)?;
-        if byte == Opcode::Copy as u8 || byte == Opcode::Swap as u8 {
+        if byte == Opcode::Return as u8 {
+            return Some(Op::Return);
+        }
+        let mix = if byte == Opcode::Add as u8 {
+            Some(Mix::Add)
+        } else if byte == Opcode::Multiply as u8 {
+            Some(Mix::Multiply)
+        } else if byte == Opcode::Average as u8 {
+            Some(Mix::Average)
+        } else if byte == Opcode::Max as u8 {
+            Some(Mix::Max)
+        } else {
+            None
+        };
+        if byte == Opcode::Copy as u8
+            || byte == Opcode::Swap as u8
+            || byte == Opcode::HalfSpeed as u8
+            || byte == Opcode::DoubleSpeed as u8
+            || mix.is_some()
+        {
             let i = *bytecode.get(self.state.pc + 1)? as usize;
             self.state.pc += 1;
             let j = *bytecode.get(self.state.pc + 1)? as usize;
             self.state.pc += 1;
 
-            if byte == Opcode::Swap as u8 {
-                return Some(Op::Swap(i % REGISTER_COUNT, j % REGISTER_COUNT));
+            let (i, j) = (i % REGISTER_COUNT, j % REGISTER_COUNT);
+            if let Some(mix) = mix {
+                return Some(Op::Mix(mix, i, j));
+            } else if byte == Opcode::Swap as u8 {
+                return Some(Op::Swap(i, j));
+            } else if byte == Opcode::HalfSpeed as u8 {
+                return Some(Op::HalfSpeed(i, j));
+            } else if byte == Opcode::DoubleSpeed as u8 {
+                return Some(Op::DoubleSpeed(i, j));
             } else {
-                return Some(Op::Copy(i % REGISTER_COUNT, j % REGISTER_COUNT));
+                return Some(Op::Copy(i, j));
             }
         } else {
             let i = *bytecode.get(self.state.pc + 1)? as usize;
             if byte == Opcode::Jump as u8 {
                 self.state.pc += 1;
                 return Some(Op::Jump(i % REGISTER_COUNT));
+            } else if byte == Opcode::JumpAddress as u8 {
+                self.state.pc += 1;
+                // An address like [Op::Call]'s, so jumps land exactly on labels
+                return Some(Op::JumpAddress(i));
             } else if byte == Opcode::CopyFromSelf as u8 {
                 let pc = self.state.pc;
                 self.state.pc += 1;
@@ -83,19 +173,67 @@ impl Vm {
             } else if byte == Opcode::Sample as u8 {
                 self.state.pc += 1;
                 return Some(Op::Sample(i % REGISTER_COUNT));
+            } else if byte == Opcode::Reverse as u8 {
+                self.state.pc += 1;
+                return Some(Op::Reverse(i % REGISTER_COUNT));
+            } else if byte == Opcode::Random as u8 {
+                self.state.pc += 1;
+                return Some(Op::Random(i % REGISTER_COUNT));
+            } else if byte == Opcode::Crush as u8 || byte == Opcode::Decimate as u8 {
+                // The amount is a raw byte rather than a chunk index, so self-modification can sweep it
+                let amount = *bytecode.get(self.state.pc + 2)?;
+                self.state.pc += 2;
+                if byte == Opcode::Crush as u8 {
+                    return Some(Op::Crush(i % REGISTER_COUNT, 1 + amount as u32 % 16));
+                } else {
+                    return Some(Op::Decimate(i % REGISTER_COUNT, 1 + amount as usize));
+                }
+            } else if byte == Opcode::Io as u8 {
+                // Like the [Op::Call] address, the destination doesn't wrap to a chunk
+                let addr = *bytecode.get(self.state.pc + 2)? as usize;
+                self.state.pc += 2;
+                return Some(Op::Io(i % IO_PAGE_LEN, addr));
+            } else if byte == Opcode::CopyRelative as u8 {
+                // The offset is signed, so self-modification can move it either way
+                let from = (self.state.pc as isize + i as u8 as i8 as isize)
+                    .rem_euclid(REGISTER_COUNT as isize) as usize;
+                let j = *bytecode.get(self.state.pc + 2)? as usize;
+                self.state.pc += 2;
+                return Some(Op::Copy(from, j % REGISTER_COUNT));
+            } else if byte == Opcode::JumpTable as u8 {
+                // `[JumpTable, start, len, cursor]`, where the cursor picks the entry and wraps at `len`
+                let len = (*bytecode.get(self.state.pc + 2)?).max(1);
+                let cursor = *bytecode.get(self.state.pc + 3)? % len;
+                let cursor_addr = self.state.pc + 3;
+                self.state.pc += 3;
+                return Some(Op::JumpTable(
+                    i + cursor as usize,
+                    cursor_addr,
+                    (cursor + 1) % len,
+                ));
+            } else if byte == Opcode::Call as u8 {
+                self.state.pc += 1;
+                // An address rather than a chunk index, so no wrapping
+                return Some(Op::Call(i));
             }
         }
         None
     }
 
-    #[instrument(skip(self, bytecode, backend))]
+    /// Run a single [Op] on the bytecode and the backend.
+    ///
+    /// Returns whether the PC should move on to the next instruction, which is false when the op has already moved it.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, bytecode, backend))
+    )]
     fn run_op<B: Backend>(
         &mut self,
         op: Op,
         bytecode: &mut [u8],
         backend: &mut B,
         self_modify: bool,
-    ) {
+    ) -> bool {
         let chunk_size_bytecode = bytecode.len() / REGISTER_COUNT;
         match op {
             Op::Copy(from_idx, to_idx) => {
@@ -114,6 +252,19 @@ impl Vm {
                 tracy_client::plot!("Op::Jump", 1.0);
                 backend.run(bytecode, Op::Jump(i), &self.state);
             }
+            Op::JumpAddress(addr) => {
+                self.state.pc = addr;
+                #[cfg(feature = "tracing")]
+                tracy_client::plot!("Op::JumpAddress", 1.0);
+                backend.run(bytecode, Op::JumpAddress(addr), &self.state);
+                return false;
+            }
+            Op::JumpTable(addr, cursor_addr, next) => {
+                bytecode[cursor_addr] = next;
+                self.state.pc = addr;
+                backend.run(bytecode, Op::JumpAddress(addr), &self.state);
+                return false;
+            }
             Op::Sample(i) => {
                 backend.run(bytecode, Op::Sample(i), &self.state);
             }
@@ -130,12 +281,132 @@ impl Vm {
                 }
                 backend.run(bytecode, Op::Swap(i, j), &self.state);
             }
+            Op::Reverse(i) => {
+                if self_modify {
+                    let chunk_start = i * chunk_size_bytecode;
+                    bytecode[chunk_start..chunk_start + chunk_size_bytecode].reverse();
+                    #[cfg(feature = "tracing")]
+                    tracy_client::plot!("bytecode Op::Reverse", 1.0);
+                }
+                backend.run(bytecode, Op::Reverse(i), &self.state);
+            }
+            Op::HalfSpeed(i, j) => {
+                if self_modify {
+                    // Walk backwards so that `i == j` never reads a byte it has already written
+                    for offset in (0..chunk_size_bytecode).rev() {
+                        bytecode[(j * chunk_size_bytecode) + offset] =
+                            bytecode[(i * chunk_size_bytecode) + offset / 2];
+                    }
+                    #[cfg(feature = "tracing")]
+                    tracy_client::plot!("bytecode Op::HalfSpeed", 1.0);
+                }
+                backend.run(bytecode, Op::HalfSpeed(i, j), &self.state);
+            }
+            Op::DoubleSpeed(i, j) => {
+                if self_modify {
+                    let half = chunk_size_bytecode.div_ceil(2);
+                    let to_start = j * chunk_size_bytecode;
+                    for offset in 0..half {
+                        bytecode[to_start + offset] =
+                            bytecode[(i * chunk_size_bytecode) + offset * 2];
+                    }
+                    bytecode.copy_within(
+                        to_start..to_start + (chunk_size_bytecode - half),
+                        to_start + half,
+                    );
+                    #[cfg(feature = "tracing")]
+                    tracy_client::plot!("bytecode Op::DoubleSpeed", 1.0);
+                }
+                backend.run(bytecode, Op::DoubleSpeed(i, j), &self.state);
+            }
+            Op::Random(i) => {
+                let byte = self.state.rng.next_u8();
+                if self_modify {
+                    bytecode[self.state.pc] = byte;
+                    #[cfg(feature = "tracing")]
+                    tracy_client::plot!("bytecode Op::Random", 1.0);
+                }
+                let from_idx = byte as usize % REGISTER_COUNT;
+                backend.run(bytecode, Op::Copy(from_idx, i), &self.state);
+            }
+            Op::Mix(mix, i, j) => {
+                if self_modify {
+                    for offset in 0..chunk_size_bytecode {
+                        let to = (j * chunk_size_bytecode) + offset;
+                        bytecode[to] = mix
+                            .bytecode(bytecode[(i * chunk_size_bytecode) + offset], bytecode[to]);
+                    }
+                    #[cfg(feature = "tracing")]
+                    tracy_client::plot!("bytecode Op::Mix", 1.0);
+                }
+                backend.run(bytecode, Op::Mix(mix, i, j), &self.state);
+            }
+            Op::Crush(i, bits) => {
+                if self_modify && bits < 8 {
+                    let chunk_start = i * chunk_size_bytecode;
+                    let mask = u8::MAX << (8 - bits);
+                    for byte in &mut bytecode[chunk_start..chunk_start + chunk_size_bytecode] {
+                        *byte &= mask;
+                    }
+                    #[cfg(feature = "tracing")]
+                    tracy_client::plot!("bytecode Op::Crush", 1.0);
+                }
+                backend.run(bytecode, Op::Crush(i, bits), &self.state);
+            }
+            Op::Decimate(i, hold) => {
+                if self_modify {
+                    let chunk_start = i * chunk_size_bytecode;
+                    for offset in 0..chunk_size_bytecode {
+                        bytecode[chunk_start + offset] =
+                            bytecode[chunk_start + offset - offset % hold];
+                    }
+                    #[cfg(feature = "tracing")]
+                    tracy_client::plot!("bytecode Op::Decimate", 1.0);
+                }
+                backend.run(bytecode, Op::Decimate(i, hold), &self.state);
+            }
+            Op::Io(slot, addr) => {
+                let len = bytecode.len();
+                bytecode[addr % len] = self.state.io[slot];
+                #[cfg(feature = "tracing")]
+                tracy_client::plot!("Op::Io", 1.0);
+                backend.run(bytecode, Op::Io(slot, addr), &self.state);
+            }
+            Op::Call(addr) => {
+                if self.state.sp == STACK_DEPTH {
+                    self.state.halted = true;
+                    return true;
+                }
+                // Return to the instruction after this one
+                self.state.stack[self.state.sp] = self.state.pc + 1;
+                self.state.sp += 1;
+                self.state.pc = addr;
+                #[cfg(feature = "tracing")]
+                tracy_client::plot!("Op::Call", 1.0);
+                backend.run(bytecode, Op::Call(addr), &self.state);
+                return false;
+            }
+            Op::Return => {
+                if self.state.sp == 0 {
+                    self.state.halted = true;
+                    return true;
+                }
+                self.state.sp -= 1;
+                self.state.pc = self.state.stack[self.state.sp];
+                #[cfg(feature = "tracing")]
+                tracy_client::plot!("Op::Return", 1.0);
+                backend.run(bytecode, Op::Return, &self.state);
+                return false;
+            }
             _ => {}
         }
+        true
     }
 
-    fn increment(&mut self) {
-        self.state.pc += 1;
+    fn increment(&mut self, advance: bool) {
+        if advance {
+            self.state.pc += 1;
+        }
         self.state.total_for_run += 1;
         self.state.buf_index += 1;
     }
@@ -149,20 +420,307 @@ impl Vm {
             .store(self.state.buf_index, std::sync::atomic::Ordering::Relaxed);
     }
 
+    pub fn set_max_instructions(&mut self, max_instructions: usize) {
+        self.max_instructions = max_instructions;
+    }
+
+    /// Set the maximum total [Op::cost] for each run
+    pub fn set_max_cost(&mut self, max_cost: usize) {
+        self.max_cost = max_cost;
+    }
+
+    /// Reseed the random number generator, so that runs from here on are reproducible
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.state.rng = Rng::new(seed);
+    }
+
+    /// Choose how [Op::Sample] turns audio into bytecode
+    pub fn set_sampling(&mut self, mode: SampleMode, curve: Curve) {
+        self.state.sample_mode = mode;
+        self.state.curve = curve;
+    }
+
+    /// Update the I/O page for the runs from here on, see [HostContext::page](crate::io::HostContext::page)
+    pub fn set_io(&mut self, page: [u8; IO_PAGE_LEN]) {
+        self.state.io = page;
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn state(&self) -> &VmState {
+        &self.state
+    }
+
     /// Prepare for the next run
     fn reset(&mut self) {
         self.state.pc = 0;
         self.state.total_for_run = 0;
+        self.state.cost_for_run = 0;
         self.state.buf_index = 0;
+        self.state.sp = 0;
+        self.state.halted = false;
     }
 }
 
 impl Default for Vm {
     fn default() -> Self {
-        Self {
-            max_instructions: 512,
-            state: VmState::default(),
-            ui_counters: (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0))),
+        Self::new(512)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use dasp::ring_buffer::Fixed;
+    use proptest::prelude::*;
+
+    use super::Vm;
+    use crate::{
+        backend::NoopBackend,
+        io::{HostContext, IoSlot},
+        op::{Op, Opcode},
+        state::STACK_DEPTH,
+    };
+
+    const CALL: u8 = Opcode::Call as u8;
+    const RETURN: u8 = Opcode::Return as u8;
+    const JUMP: u8 = Opcode::Jump as u8;
+    const JUMP_ADDRESS: u8 = Opcode::JumpAddress as u8;
+    const ADD: u8 = Opcode::Add as u8;
+    const CRUSH: u8 = Opcode::Crush as u8;
+    const DECIMATE: u8 = Opcode::Decimate as u8;
+    const IO: u8 = Opcode::Io as u8;
+    const COPY_RELATIVE: u8 = Opcode::CopyRelative as u8;
+    const JUMP_TABLE: u8 = Opcode::JumpTable as u8;
+    const REVERSE: u8 = Opcode::Reverse as u8;
+    const HALF_SPEED: u8 = Opcode::HalfSpeed as u8;
+    const DOUBLE_SPEED: u8 = Opcode::DoubleSpeed as u8;
+    const RANDOM: u8 = Opcode::Random as u8;
+
+    #[test]
+    fn test_call_and_return() {
+        // 0: call 4, 2: call 4, 4: return
+        let mut bytecode = [CALL, 4, CALL, 4, RETURN];
+        let mut vm = Vm::default();
+        vm.run(&mut bytecode, &mut NoopBackend, false);
+        let state = vm.state();
+        // Both calls come back, then the final return finds an empty stack
+        assert!(state.halted);
+        assert_eq!(state.sp, 0);
+        assert_eq!(state.total_for_run, 5);
+    }
+
+    #[test]
+    fn test_mix_bytecode() {
+        // Two byte chunks, so `add 0 1` adds [ADD, 0] into [1, 5]
+        let mut bytecode = [0; 32];
+        bytecode[..4].copy_from_slice(&[ADD, 0, 1, 5]);
+        let mut vm = Vm::new(0);
+        vm.run(&mut bytecode, &mut NoopBackend, true);
+        assert_eq!(&bytecode[..4], &[ADD, 0, ADD + 1, 5]);
+    }
+
+    #[test]
+    fn test_reverse_and_speed_changes_bytecode() {
+        // Four byte chunks, with chunks 1 and 2 counting up
+        let run = |op: &[u8]| {
+            let mut bytecode = [0; 64];
+            bytecode[..op.len()].copy_from_slice(op);
+            bytecode[4..12].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+            let mut vm = Vm::new(0);
+            vm.run(&mut bytecode, &mut NoopBackend, true);
+            bytecode[4..12].to_vec()
+        };
+        assert_eq!(run(&[REVERSE, 1]), [4, 3, 2, 1, 5, 6, 7, 8]);
+        assert_eq!(run(&[HALF_SPEED, 1, 2]), [1, 2, 3, 4, 1, 1, 2, 2]);
+        assert_eq!(run(&[DOUBLE_SPEED, 1, 2]), [1, 2, 3, 4, 1, 3, 1, 3]);
+        // In place, without reading bytes already written
+        assert_eq!(run(&[HALF_SPEED, 1, 1]), [1, 1, 2, 2, 5, 6, 7, 8]);
+        assert_eq!(run(&[DOUBLE_SPEED, 1, 1]), [1, 3, 1, 3, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_random_follows_the_seed() {
+        // Each random op writes its byte over its own operand
+        let run = |seed| {
+            let mut bytecode = [RANDOM, 0].repeat(16);
+            let mut vm = Vm::default();
+            vm.set_seed(seed);
+            vm.run(&mut bytecode, &mut NoopBackend, true);
+            bytecode
+        };
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+    }
+
+    #[test]
+    fn test_crush_depth_comes_from_the_next_byte() {
+        // Four byte chunks. A depth byte of 1 crushes chunk 1 to 2 bits.
+        let mut bytecode = [0; 64];
+        bytecode[..8].copy_from_slice(&[CRUSH, 1, 1, 0, 0xff, 0x7f, 0x3f, 0x1f]);
+        let mut vm = Vm::new(0);
+        vm.run(&mut bytecode, &mut NoopBackend, true);
+        assert_eq!(&bytecode[4..8], &[0xc0, 0x40, 0, 0]);
+    }
+
+    #[test]
+    fn test_decimate_holds_bytes() {
+        // Four byte chunks, each byte of chunk 1 held for 2
+        let mut bytecode = [0; 64];
+        bytecode[..8].copy_from_slice(&[DECIMATE, 1, 1, 0, 1, 2, 3, 4]);
+        let mut vm = Vm::new(0);
+        vm.run(&mut bytecode, &mut NoopBackend, true);
+        assert_eq!(&bytecode[4..8], &[1, 1, 3, 3]);
+    }
+
+    #[test]
+    fn test_io_writes_the_page_to_a_raw_address() {
+        // The second op's slot wraps around to the tempo too
+        let mut bytecode = [0; 64];
+        bytecode[..6].copy_from_slice(&[IO, IoSlot::Tempo as u8, 40, IO, 16, 41]);
+        let mut vm = Vm::new(2);
+        vm.set_io(
+            HostContext {
+                tempo: Some(120.0),
+                ..Default::default()
+            }
+            .page(),
+        );
+        vm.run(&mut bytecode, &mut NoopBackend, false);
+        assert_eq!(&bytecode[40..42], &[120, 120]);
+    }
+
+    #[test]
+    fn test_copy_relative_wraps_around_the_chunks() {
+        let mut bytecode = [COPY_RELATIVE, -1i8 as u8, 20, COPY_RELATIVE, 14, 5];
+        let mut vm = Vm::default();
+        assert!(matches!(vm.parse_op(&mut bytecode), Some(Op::Copy(15, 4))));
+        vm.state.pc = 3;
+        assert!(matches!(vm.parse_op(&mut bytecode), Some(Op::Copy(1, 5))));
+    }
+
+    #[test]
+    fn test_jump_lands_after_its_chunk_index() {
+        // 0: jump to 17, which wraps to 1 and resumes at 2, 2: jump to 4, which resumes at 5, 5: noop
+        let mut bytecode = [JUMP, 17, JUMP, 4, 0, 0];
+        let mut vm = Vm::new(4);
+        vm.run(&mut bytecode, &mut NoopBackend, false);
+        assert_eq!(vm.state().total_for_run, 3);
+        assert_eq!(vm.state().pc, 6);
+    }
+
+    #[test]
+    fn test_jump_address_lands_on_its_address() {
+        // 0: jump 4, 2: jump 0, 4: jump 2
+        let mut bytecode = [JUMP_ADDRESS, 4, JUMP_ADDRESS, 0, JUMP_ADDRESS, 2];
+        let mut vm = Vm::new(4);
+        vm.run(&mut bytecode, &mut NoopBackend, false);
+        assert_eq!(vm.state().pc, 2);
+    }
+
+    #[test]
+    fn test_jump_table_takes_the_next_entry_each_run() {
+        let mut bytecode = [0; 16];
+        bytecode[..4].copy_from_slice(&[JUMP_TABLE, 10, 3, 0]);
+        let mut vm = Vm::new(0);
+        for (addr, cursor) in [(10, 1), (11, 2), (12, 0), (10, 1)] {
+            vm.run(&mut bytecode, &mut NoopBackend, false);
+            assert_eq!(vm.state().pc, addr);
+            assert_eq!(bytecode[3], cursor);
+        }
+    }
+
+    #[test]
+    fn test_unbounded_recursion_halts_at_stack_depth() {
+        let mut bytecode = [CALL, 0];
+        let mut vm = Vm::new(512);
+        vm.run(&mut bytecode, &mut NoopBackend, false);
+        let state = vm.state();
+        assert!(state.halted);
+        assert_eq!(state.sp, STACK_DEPTH);
+        assert_eq!(state.total_for_run, STACK_DEPTH + 1);
+    }
+
+    #[test]
+    fn test_max_instructions_cuts_off_recursion_first() {
+        let mut bytecode = [CALL, 0];
+        let mut vm = Vm::new(STACK_DEPTH / 2);
+        vm.run(&mut bytecode, &mut NoopBackend, false);
+        let state = vm.state();
+        assert!(!state.halted);
+        assert_eq!(state.total_for_run, STACK_DEPTH / 2 + 1);
+    }
+
+    #[test]
+    fn test_call_loop_is_bounded_by_max_instructions() {
+        // 0: noop, 1: call 5, 3: jump to 0 (which resumes at 1), 5: return
+        let mut bytecode = [0, CALL, 5, JUMP, 0, RETURN];
+        let mut vm = Vm::new(100);
+        vm.run(&mut bytecode, &mut NoopBackend, false);
+        let state = vm.state();
+        assert!(!state.halted);
+        assert_eq!(state.total_for_run, 101);
+        assert!(state.sp <= 1);
+    }
+
+    proptest! {
+        #[test]
+        fn test_arbitrary_bytecode_never_overflows_the_stack(
+            mut bytecode in prop::collection::vec(any::<u8>(), 0..64),
+            max_instructions in 0..1024usize,
+        ) {
+            let mut vm = Vm::new(max_instructions);
+            vm.run(&mut bytecode, &mut NoopBackend, true);
+            prop_assert!(vm.state().sp <= STACK_DEPTH);
+            prop_assert!(vm.state().total_for_run <= max_instructions + 1);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_arbitrary_bytecode_stays_within_cost_budget(
+            mut bytecode in prop::collection::vec(any::<u8>(), 0..512),
+            max_cost in 0..1usize << 16,
+        ) {
+            let mut buffer = Fixed::from(vec![[0.0, 0.0]; 8192]);
+            let mut vm = Vm::new(usize::MAX);
+            vm.set_max_cost(max_cost);
+            vm.run(&mut bytecode, &mut buffer, true);
+            prop_assert!(vm.state().cost_for_run <= max_cost);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(
+        debug_assertions,
+        ignore = "timing is only meaningful in release builds"
+    )]
+    fn test_worst_case_time_is_bounded() {
+        let max_cost = 1 << 18;
+        let programs = [Opcode::Swap, Opcode::Copy, Opcode::Reverse, Opcode::Jump].map(|opcode| {
+            [opcode as u8, 0, 15]
+                .into_iter()
+                .cycle()
+                .take(510)
+                .collect::<Vec<_>>()
+        });
+        let mut buffer = Fixed::from(vec![[0.5, -0.5]; 8192]);
+        for mut bytecode in programs {
+            let mut vm = Vm::new(usize::MAX);
+            vm.set_max_cost(max_cost);
+            let start = Instant::now();
+            vm.run(&mut bytecode, &mut buffer, true);
+            let elapsed = start.elapsed();
+            assert!(
+                elapsed < Duration::from_millis(5),
+                "opcode {} took {:?}",
+                bytecode[0],
+                elapsed
+            );
         }
     }
 }
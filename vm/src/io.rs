@@ -0,0 +1,127 @@
+/// The number of bytes in the I/O page
+pub const IO_PAGE_LEN: usize = 16;
+/// The number of macro parameters on the I/O page
+pub const MACRO_COUNT: usize = 4;
+
+/// The bytes of the I/O page, which the host fills in each block and [Op::Io](crate::op::Op::Io) reads.
+/// Bytes past the last slot are reserved and read as 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoSlot {
+    /// The tempo in BPM, up to 255
+    Tempo,
+    /// How far through the current beat the transport is
+    Beat,
+    /// How far through the current bar the transport is
+    Bar,
+    /// The number of whole bars played, wrapping at 256
+    BarCount,
+    /// 255 while the transport is playing, 0 when stopped
+    Playing,
+    /// The peak level of the incoming block
+    Level,
+    Macro1,
+    Macro2,
+    Macro3,
+    Macro4,
+}
+
+impl IoSlot {
+    pub const ALL: [IoSlot; 10] = [
+        IoSlot::Tempo,
+        IoSlot::Beat,
+        IoSlot::Bar,
+        IoSlot::BarCount,
+        IoSlot::Playing,
+        IoSlot::Level,
+        IoSlot::Macro1,
+        IoSlot::Macro2,
+        IoSlot::Macro3,
+        IoSlot::Macro4,
+    ];
+
+    /// What the slot is called in programs
+    pub fn name(self) -> &'static str {
+        match self {
+            IoSlot::Tempo => "tempo",
+            IoSlot::Beat => "beat",
+            IoSlot::Bar => "bar",
+            IoSlot::BarCount => "bars",
+            IoSlot::Playing => "playing",
+            IoSlot::Level => "level",
+            IoSlot::Macro1 => "macro1",
+            IoSlot::Macro2 => "macro2",
+            IoSlot::Macro3 => "macro3",
+            IoSlot::Macro4 => "macro4",
+        }
+    }
+}
+
+/// What the host knows about the music at the start of a block. Anything the host doesn't report is left as
+/// `None` and reads as 0, apart from the time signature which defaults to 4/4.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HostContext {
+    pub tempo: Option<f64>,
+    /// The transport position, in quarter notes
+    pub pos_beats: Option<f64>,
+    /// Beats per bar
+    pub time_sig_numerator: Option<i32>,
+    pub playing: bool,
+    /// The peak level of the incoming block, 0..=1
+    pub input_level: f32,
+    /// The macro parameters, each 0..=1
+    pub macros: [f32; MACRO_COUNT],
+}
+
+impl HostContext {
+    /// Quantize the context into an I/O page. Positions within a beat or bar count up from 0 to 255.
+    pub fn page(&self) -> [u8; IO_PAGE_LEN] {
+        let unit = |x: f32| (x.clamp(0.0, 1.0) * 255.0).round() as u8;
+        let phase = |x: f64| (x.fract() * 256.0) as u8;
+        let beats = self.pos_beats.unwrap_or(0.0).max(0.0);
+        let bars = beats / self.time_sig_numerator.unwrap_or(4).max(1) as f64;
+
+        let mut page = [0; IO_PAGE_LEN];
+        page[IoSlot::Tempo as usize] = self.tempo.unwrap_or(0.0).clamp(0.0, 255.0).round() as u8;
+        page[IoSlot::Beat as usize] = phase(beats);
+        page[IoSlot::Bar as usize] = phase(bars);
+        page[IoSlot::BarCount as usize] = (bars as u64 % 256) as u8;
+        page[IoSlot::Playing as usize] = if self.playing { u8::MAX } else { 0 };
+        page[IoSlot::Level as usize] = unit(self.input_level);
+        for (slot, value) in page[IoSlot::Macro1 as usize..].iter_mut().zip(self.macros) {
+            *slot = unit(value);
+        }
+        page
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HostContext, IoSlot};
+
+    #[test]
+    fn test_page() {
+        let context = HostContext {
+            tempo: Some(120.4),
+            pos_beats: Some(22.5),
+            time_sig_numerator: Some(3),
+            playing: true,
+            input_level: 0.5,
+            macros: [0.0, 1.0, 2.0, -1.0],
+        };
+        let page = context.page();
+        assert_eq!(page[IoSlot::Tempo as usize], 120);
+        assert_eq!(page[IoSlot::Beat as usize], 128);
+        // 7.5 bars of 3/4
+        assert_eq!(page[IoSlot::Bar as usize], 128);
+        assert_eq!(page[IoSlot::BarCount as usize], 7);
+        assert_eq!(page[IoSlot::Playing as usize], 255);
+        assert_eq!(page[IoSlot::Level as usize], 128);
+        assert_eq!(page[IoSlot::Macro1 as usize..][..4], [0, 255, 255, 0]);
+        assert!(page[IoSlot::ALL.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_unknown_context_reads_as_zero() {
+        assert_eq!(HostContext::default().page(), [0; super::IO_PAGE_LEN]);
+    }
+}
@@ -1,6 +1,10 @@
 pub mod backend;
+pub mod cores;
 pub mod interpret;
+pub mod io;
 pub mod op;
+pub mod rng;
+pub mod sample;
 pub mod state;
 
 pub const REGISTER_COUNT: usize = 16;
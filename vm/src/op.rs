@@ -1,3 +1,5 @@
+use crate::sample::SampleMode;
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Opcode {
     /// Allow for extra space in the bytecode
@@ -8,12 +10,91 @@ pub enum Opcode {
     CopyFromSelf,
     /// Flip the data at `i`. For the bytecode this is a binary NOT, for samples this is `1 - sample`
     Flip,
-    /// Jump from `i` to `j`. For the bytecode this changes the PC, for the buffer it causes an audible skip
+    /// Continue from the address after `i`, with `i` wrapped to a chunk index. For the bytecode this changes the PC,
+    /// for the buffer it causes an audible skip
     Jump,
-    /// Copy sample `i` from the audio buffer into the bytecode. If there are multiple channels this will be the product.
+    /// Sample chunk `i` of the audio buffer into the bytecode, as chosen by the [SampleMode](crate::sample::SampleMode) and [Curve](crate::sample::Curve)
     Sample,
     /// Swap chunk `i` and `j` in the audio buffer and byte `i` for `j` in the bytecode.
     Swap,
+    /// Reverse chunk `i` in place, in both the bytecode and the audio buffer
+    Reverse,
+    /// Stretch the first half of chunk `i` over chunk `j` at 0.5x, like a tape stop. In the bytecode each byte is doubled up.
+    HalfSpeed,
+    /// Squeeze chunk `i` into the first half of chunk `j` at 2x and play it twice, for a pitched stutter. In the bytecode every other byte is dropped.
+    DoubleSpeed,
+    /// Copy a random chunk into chunk `i` in the audio buffer, and overwrite `i` in the bytecode with the random byte that picked it.
+    Random,
+    /// Push the return address and continue from address `i`. Halts the run if the stack is full.
+    Call,
+    /// Continue from the address on top of the stack. Halts the run if the stack is empty.
+    Return,
+    /// Mix chunk `i` into chunk `j` with [Mix::Add]
+    Add,
+    /// Mix chunk `i` into chunk `j` with [Mix::Multiply]
+    Multiply,
+    /// Mix chunk `i` into chunk `j` with [Mix::Average]
+    Average,
+    /// Mix chunk `i` into chunk `j` with [Mix::Max]
+    Max,
+    /// Requantize chunk `i` to a bit depth taken from the next byte, see [Op::Crush]
+    Crush,
+    /// Sample-and-hold chunk `i` at a rate taken from the next byte, see [Op::Decimate]
+    Decimate,
+    /// Copy byte `i` of the I/O page into the bytecode at the raw address in the next byte, see [Op::Io]
+    Io,
+    /// Copy from chunk `pc + i` to `j`, where `i` is signed
+    CopyRelative,
+    /// Continue from address `i` plus a cursor, which counts up to the next byte and wraps, see [Op::JumpTable]
+    JumpTable,
+    /// Continue from address `i` exactly, as jumps to labels do. Unlike [Opcode::Jump] it isn't wrapped to a chunk.
+    JumpAddress,
+}
+
+/// Ways of combining chunk `i` into chunk `j`, sample by sample in the audio buffer and byte by byte in the bytecode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mix {
+    /// Sum the samples, soft-clipped so they stay within -1..1. Wrapping addition in the bytecode.
+    Add,
+    /// Ring modulate the samples. XOR in the bytecode.
+    Multiply,
+    /// The mean of the samples. AND in the bytecode.
+    Average,
+    /// Whichever sample is louder. The larger byte in the bytecode.
+    Max,
+}
+
+/// A cubic soft clipper, flat at ±1 beyond ±1.5. Far cheaper than `tanh`, which would make [Mix::Add] cost much
+/// more than its [Op::cost] says.
+fn soft_clip(sample: f32) -> f32 {
+    let sample = sample.clamp(-1.5, 1.5);
+    sample - (4.0 / 27.0) * sample * sample * sample
+}
+
+impl Mix {
+    pub fn audio(self, i: f32, j: f32) -> f32 {
+        match self {
+            Mix::Add => soft_clip(i + j),
+            Mix::Multiply => i * j,
+            Mix::Average => (i + j) / 2.0,
+            Mix::Max => {
+                if i.abs() > j.abs() {
+                    i
+                } else {
+                    j
+                }
+            }
+        }
+    }
+
+    pub fn bytecode(self, i: u8, j: u8) -> u8 {
+        match self {
+            Mix::Add => i.wrapping_add(j),
+            Mix::Multiply => i ^ j,
+            Mix::Average => i & j,
+            Mix::Max => i.max(j),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -21,6 +102,85 @@ pub enum Op {
     Copy(usize, usize),
     Flip(usize),
     Jump(usize),
+    JumpAddress(usize),
     Sample(usize),
     Swap(usize, usize),
+    Reverse(usize),
+    HalfSpeed(usize, usize),
+    DoubleSpeed(usize, usize),
+    Random(usize),
+    Call(usize),
+    Return,
+    Mix(Mix, usize, usize),
+    /// Chunk `i` and a depth of `1..=16` bits. The bytecode is crushed to at most 8.
+    Crush(usize, u32),
+    /// Chunk `i` and how many frames (or bytes) to hold each one for, `1..=256`
+    Decimate(usize, usize),
+    /// An [IoSlot](crate::io::IoSlot) index and the address in the bytecode to copy it to. The bytecode is
+    /// written whether or not it's self-modifying, since that's the point.
+    Io(usize, usize),
+    /// The address to continue from, the address of the cursor that chose it and the cursor's next value. Like
+    /// [Op::Io] the cursor is moved on whether or not the bytecode is self-modifying, so each run of the same
+    /// bytecode takes the next entry.
+    ///
+    /// In the plugin the audio thread gets a fresh copy of the bytecode every block, so its cursor moves are lost
+    /// unless it writes its bytecode back with self-modification on audio. Otherwise the table moves on once per
+    /// run of the bytecode thread, at the bytecode rate.
+    JumpTable(usize, usize, u8),
+}
+
+impl Op {
+    /// Roughly how much work running this op takes, in frames (or bytes) touched.
+    ///
+    /// Every op costs at least 1 for decoding and writing its output frame. Ops which move whole chunks cost `chunk_size` more per chunk touched,
+    /// as does [Op::Sample] when `sample_mode` measures the whole chunk.
+    pub fn cost(&self, chunk_size: usize, sample_mode: SampleMode) -> usize {
+        1 + match self {
+            Op::Copy(_, _)
+            | Op::Reverse(_)
+            | Op::HalfSpeed(_, _)
+            | Op::DoubleSpeed(_, _)
+            | Op::Random(_)
+            | Op::Mix(_, _, _)
+            | Op::Crush(_, _)
+            | Op::Decimate(_, _) => chunk_size,
+            Op::Sample(_) if sample_mode.scans_chunk() => chunk_size,
+            Op::Swap(_, _) => 2 * chunk_size,
+            Op::Flip(_)
+            | Op::Jump(_)
+            | Op::JumpAddress(_)
+            | Op::JumpTable(_, _, _)
+            | Op::Sample(_)
+            | Op::Call(_)
+            | Op::Return
+            | Op::Io(_, _) => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{Mix, Op};
+    use crate::sample::SampleMode;
+
+    #[test]
+    fn test_sample_costs_the_chunk_when_it_scans_it() {
+        assert_eq!(Op::Sample(0).cost(64, SampleMode::Mid), 1);
+        assert_eq!(Op::Sample(0).cost(64, SampleMode::Peak), 65);
+        assert_eq!(Op::Sample(0).cost(64, SampleMode::Rms), 65);
+    }
+
+    proptest! {
+        #[test]
+        fn test_mixed_audio_stays_bounded(
+            mix in prop::sample::select(&[Mix::Add, Mix::Multiply, Mix::Average, Mix::Max]),
+            i in -1.0..=1.0f32,
+            j in -1.0..=1.0f32,
+        ) {
+            let mixed = mix.audio(i, j);
+            prop_assert!((-1.0..=1.0).contains(&mixed), "{:?} of {} and {} gave {}", mix, i, j, mixed);
+        }
+    }
 }
@@ -0,0 +1,53 @@
+/// A small xorshift PRNG, so the VM's randomness is seedable and never allocates or locks on the audio thread
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Run the seed through splitmix64 so that nearby seeds diverge, and so the state is never 0,
+        // which xorshift would get stuck on
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        Self { state: z.max(1) }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::Rng;
+
+    proptest! {
+        #[test]
+        fn test_same_seed_same_sequence(seed: u64) {
+            let (mut a, mut b) = (Rng::new(seed), Rng::new(seed));
+            for _ in 0..64 {
+                prop_assert_eq!(a.next_u64(), b.next_u64());
+            }
+        }
+    }
+}
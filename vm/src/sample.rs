@@ -0,0 +1,133 @@
+//! How [Op::Sample](crate::op::Op::Sample) turns a chunk of audio into a byte of bytecode
+
+use numquant::linear;
+
+/// Which part of the audio a sample is taken from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SampleMode {
+    /// The first channel of the chunk's first frame
+    Left,
+    /// The second channel of the chunk's first frame, or the only one in mono
+    Right,
+    /// The average of all the channels of the chunk's first frame
+    #[default]
+    Mid,
+    /// Half the difference between the first two channels of the chunk's first frame. Always 0 in mono.
+    Side,
+    /// The loudest sample in the chunk, from any channel
+    Peak,
+    /// The RMS level of the chunk, over all channels
+    Rms,
+}
+
+/// How a sample's level is spread over the 256 possible bytes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// μ-law companding, which gives quiet samples more of the range
+    MuLaw,
+    /// Decibels over a 60dB range, so anything quieter is silence
+    Log,
+}
+
+const MU: f32 = 255.0;
+const LOG_RANGE_DB: f32 = 60.0;
+
+impl SampleMode {
+    /// Whether [Self::measure] goes through every frame of the chunk
+    pub fn scans_chunk(self) -> bool {
+        matches!(self, SampleMode::Peak | SampleMode::Rms)
+    }
+
+    /// Measure a chunk of frames. Only [SampleMode::Peak] and [SampleMode::Rms] look beyond the first frame.
+    pub fn measure<const C: usize>(self, chunk: impl IntoIterator<Item = [f32; C]>) -> f32 {
+        let mut chunk = chunk.into_iter();
+        match self {
+            SampleMode::Peak => chunk.flatten().map(f32::abs).fold(0.0, f32::max),
+            SampleMode::Rms => {
+                let (sum, count) = chunk
+                    .flatten()
+                    .fold((0.0, 0), |(sum, count), s| (sum + s * s, count + 1));
+                if count == 0 {
+                    0.0
+                } else {
+                    (sum / count as f32).sqrt()
+                }
+            }
+            _ => {
+                let frame = chunk.next().unwrap_or([0.0; C]);
+                let (left, right) = (frame[0], frame[C.min(2) - 1]);
+                match self {
+                    SampleMode::Left => left,
+                    SampleMode::Right => right,
+                    SampleMode::Side => (left - right) / 2.0,
+                    _ => frame.iter().sum::<f32>() / C as f32,
+                }
+            }
+        }
+    }
+}
+
+impl Curve {
+    /// Quantize a sample in `-1..1` to a byte, clamping anything outside it
+    pub fn quantize(self, sample: f32) -> u8 {
+        let sample = sample.clamp(-1.0, 1.0);
+        let shaped = match self {
+            Curve::Linear => sample,
+            Curve::MuLaw => sample.signum() * (1.0 + MU * sample.abs()).ln() / (1.0 + MU).ln(),
+            Curve::Log => {
+                let db = 20.0 * sample.abs().log10();
+                sample.signum() * (1.0 + db / LOG_RANGE_DB).max(0.0)
+            }
+        };
+        linear::quantize(shaped as f64, -1.0..1.0, 255u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{Curve, SampleMode};
+
+    #[test]
+    fn test_chunk_measurements() {
+        let chunk = [[0.5, -0.5], [0.0, 0.0], [-1.0, 0.0], [0.0, 0.0]];
+        assert_eq!(SampleMode::Left.measure(chunk), 0.5);
+        assert_eq!(SampleMode::Right.measure(chunk), -0.5);
+        assert_eq!(SampleMode::Mid.measure(chunk), 0.0);
+        assert_eq!(SampleMode::Side.measure(chunk), 0.5);
+        assert_eq!(SampleMode::Peak.measure(chunk), 1.0);
+        assert_eq!(SampleMode::Rms.measure(chunk), (1.5f32 / 8.0).sqrt());
+        assert_eq!(SampleMode::Rms.measure::<2>([]), 0.0);
+    }
+
+    #[test]
+    fn test_mono_and_surround_measurements() {
+        assert_eq!(SampleMode::Right.measure([[0.5]]), 0.5);
+        assert_eq!(SampleMode::Side.measure([[0.5]]), 0.0);
+        assert_eq!(
+            SampleMode::Mid.measure([[0.5, 0.5, 0.5, 0.5, 0.0, 0.0]]),
+            1.0 / 3.0
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_curves_are_monotonic(
+            curve in prop::sample::select(&[Curve::Linear, Curve::MuLaw, Curve::Log]),
+            a in -1.0..=1.0f32,
+            b in -1.0..=1.0f32,
+        ) {
+            let (low, high) = if a <= b { (a, b) } else { (b, a) };
+            prop_assert!(curve.quantize(low) <= curve.quantize(high));
+        }
+    }
+
+    #[test]
+    fn test_mu_law_favours_quiet_samples() {
+        assert!(Curve::MuLaw.quantize(0.01) > Curve::Linear.quantize(0.01));
+        assert_eq!(Curve::MuLaw.quantize(1.0), Curve::Linear.quantize(1.0));
+    }
+}
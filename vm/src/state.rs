@@ -1,3 +1,12 @@
+use crate::{
+    io::IO_PAGE_LEN,
+    rng::Rng,
+    sample::{Curve, SampleMode},
+};
+
+/// How deep [Op::Call]s can nest before the run halts
+pub const STACK_DEPTH: usize = 8;
+
 #[derive(Clone, Debug, Default)]
 pub struct VmState {
     /// The current index in both the bytecode and the audio buffer
@@ -11,4 +20,20 @@ pub struct VmState {
     pub buf_index: usize,
     /// The total instructions/samples processed. Resets to 0 after each run.
     pub total_for_run: usize,
+    /// The total [Op::cost] of the instructions processed. Resets to 0 after each run.
+    pub cost_for_run: usize,
+    /// Return addresses pushed by [Op::Call]
+    pub stack: [usize; STACK_DEPTH],
+    /// The number of return addresses on the [Self::stack]
+    pub sp: usize,
+    /// Set when the run should stop early, e.g. on stack overflow or running out of budget. Resets after each run.
+    pub halted: bool,
+    /// Drives [Op::Random]. Unlike the counters above this is not reset between runs, only reseeded.
+    pub rng: Rng,
+    /// Where [Op::Sample] takes its sample from. Set with [Vm::set_sampling](crate::interpret::Vm::set_sampling).
+    pub sample_mode: SampleMode,
+    /// How [Op::Sample] quantizes its sample
+    pub curve: Curve,
+    /// What the host knows about the music, for [Op::Io]. Set with [Vm::set_io](crate::interpret::Vm::set_io).
+    pub io: [u8; IO_PAGE_LEN],
 }
@@ -0,0 +1,179 @@
+//! The audio thread must never allocate, block or overrun its block. These tests drive the VM the
+//! way the plugin's `process` does, with an allocator that counts allocations on the current thread.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+use dasp::ring_buffer::Fixed;
+use itertools::Itertools;
+use proptest::prelude::*;
+use vm::{
+    backend::ChannelView,
+    cores::{Cores, MAX_CORES},
+    op::Opcode,
+    sample::{Curve, SampleMode},
+};
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count_allocation() {
+    // The thread local may already be gone while the thread shuts down
+    let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// The number of allocations `f` makes on this thread
+fn allocations_in(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+/// The plugin's delay buffer and bytecode sizes, and its default CPU budget per 512 frame block
+const BUFFER_LEN: usize = 8192;
+const BYTECODE_LEN: usize = 512;
+const MAX_COST: usize = 256 * 1024;
+const BUDGET_BLOCK_LEN: usize = 512;
+
+/// What `process` does with the VM for one block: push the incoming audio into the delay buffer, then run the
+/// program over it. The budget scales with the block, and per channel runs share it.
+fn process_block(
+    cores: &mut Cores,
+    buffer: &mut Fixed<Vec<[f32; 2]>>,
+    bytecode: &mut [u8],
+    block: &[[f32; 2]],
+    self_modify: bool,
+    linked: bool,
+) {
+    for frame in block {
+        buffer.push(*frame);
+    }
+    let max_cost = MAX_COST * block.len() / BUDGET_BLOCK_LEN;
+    cores.set_max_cost(if linked { max_cost } else { max_cost / 2 });
+    if linked {
+        cores.run(bytecode, buffer, self_modify);
+    } else {
+        for channel in 0..2 {
+            cores.run(bytecode, &mut ChannelView { buffer, channel }, self_modify);
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn test_processing_never_allocates(
+        mut bytecode in prop::collection::vec(any::<u8>(), BYTECODE_LEN),
+        block in prop::collection::vec(any::<[f32; 2]>().prop_map(|f| f.map(|s| s.clamp(-1.0, 1.0))), 1..1024),
+        seed: u64,
+        self_modify: bool,
+        linked: bool,
+        mode in prop::sample::select(&[SampleMode::Left, SampleMode::Side, SampleMode::Peak, SampleMode::Rms]),
+        curve in prop::sample::select(&[Curve::Linear, Curve::MuLaw, Curve::Log]),
+        count in 1..=MAX_CORES,
+        split: bool,
+    ) {
+        let mut cores = Cores::default();
+        let mut buffer = Fixed::from(vec![[0.0; 2]; BUFFER_LEN]);
+        let allocations = allocations_in(|| {
+            cores.set_count(count);
+            cores.set_split(split);
+            cores.set_seed(seed);
+            cores.set_sampling(mode, curve);
+            process_block(&mut cores, &mut buffer, &mut bytecode, &block, self_modify, linked);
+        });
+        prop_assert_eq!(allocations, 0);
+    }
+}
+
+/// Every op that touches whole chunks, repeated over the whole bytecode
+fn worst_case_programs() -> impl Iterator<Item = Vec<u8>> {
+    [
+        Opcode::Copy,
+        Opcode::Swap,
+        Opcode::Reverse,
+        Opcode::HalfSpeed,
+        Opcode::DoubleSpeed,
+        Opcode::Random,
+        Opcode::Add,
+        Opcode::Multiply,
+        Opcode::Average,
+        Opcode::Max,
+        Opcode::Crush,
+        Opcode::Decimate,
+        Opcode::Sample,
+    ]
+    .into_iter()
+    .map(|opcode| {
+        [opcode as u8, 0, 15]
+            .into_iter()
+            .cycle()
+            .take(BYTECODE_LEN)
+            .collect()
+    })
+}
+
+#[test]
+#[cfg_attr(
+    debug_assertions,
+    ignore = "timing is only meaningful in release builds"
+)]
+fn test_worst_case_time_fits_each_block_size() {
+    const SAMPLE_RATE: f32 = 44100.0;
+    for block_len in [64, 128, 256, 512, 1024, 2048] {
+        let deadline = Duration::from_secs_f32(block_len as f32 / SAMPLE_RATE);
+        let block = vec![[0.5, -0.5]; block_len];
+        for program in worst_case_programs() {
+            for (linked, count) in [true, false].into_iter().cartesian_product([1, MAX_CORES]) {
+                let mut bytecode = program.clone();
+                let mut cores = Cores::new(usize::MAX);
+                cores.set_count(count);
+                let mut buffer = Fixed::from(vec![[0.25, -0.25]; BUFFER_LEN]);
+                let start = Instant::now();
+                process_block(&mut cores, &mut buffer, &mut bytecode, &block, true, linked);
+                let elapsed = start.elapsed();
+                assert!(
+                    elapsed < deadline,
+                    "opcode {} took {:?} of a {} frame block's {:?} (linked: {}, cores: {})",
+                    program[0],
+                    elapsed,
+                    block_len,
+                    deadline,
+                    linked,
+                    count
+                );
+            }
+        }
+    }
+}
@@ -1,3 +1,33 @@
+use std::{
+    fs,
+    io::{self, Read},
+};
+
 fn main() -> nih_plug_xtask::Result<()> {
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("format") {
+        return format(args.collect());
+    }
     nih_plug_xtask::main()
 }
+
+/// Format programs in place, or from stdin to stdout when no files are given
+fn format(paths: Vec<String>) -> nih_plug_xtask::Result<()> {
+    if paths.is_empty() {
+        let mut src = String::new();
+        io::stdin().read_to_string(&mut src)?;
+        println!("{}", format_program(&src, "stdin")?);
+        return Ok(());
+    }
+    for path in paths {
+        let src = fs::read_to_string(&path)?;
+        fs::write(&path, format_program(&src, &path)? + "\n")?;
+    }
+    Ok(())
+}
+
+fn format_program(src: &str, name: &str) -> io::Result<String> {
+    // The parser has already reported the errors themselves
+    lang::format::format(src)
+        .map_err(|errs| io::Error::other(format!("{} errors parsing {name}", errs.len())))
+}